@@ -0,0 +1,393 @@
+#![warn(missing_docs)]
+
+//! Parse and compare the package names and versions, as described by the Package Manager Specification (PMS)
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// The suffixes a version can have, in their order (a version without suffix is between `Rc` and `P`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Suffix {
+    /// `_alpha`
+    Alpha,
+    /// `_beta`
+    Beta,
+    /// `_pre`
+    Pre,
+    /// `_rc`
+    Rc,
+    /// `_p`
+    P,
+}
+
+impl Suffix {
+    /// The text of each suffix. `pre` is before `p`, as they start the same way
+    const NAMES: [(&'static str, Suffix); 5] = [
+        ("alpha", Suffix::Alpha),
+        ("beta", Suffix::Beta),
+        ("pre", Suffix::Pre),
+        ("rc", Suffix::Rc),
+        ("p", Suffix::P),
+    ];
+}
+
+/// The version of a package: `1.2.3b_rc1_p2-r1`
+///
+/// Two versions are equal if PMS says so, even if written differently (`1.0` and `1.0-r0`).
+#[derive(Clone, Debug)]
+pub struct Version {
+    /// The numeric components (`1`, `2`, `3`), kept as text to handle the leading zeros
+    numbers: Vec<String>,
+    /// The letter after the numbers (`b`)
+    letter: Option<char>,
+    /// The suffixes with their number (`_rc1_p2`, 0 if the number is missing)
+    suffixes: Vec<(Suffix, u64)>,
+    /// The revision (`-r1`, 0 if missing)
+    revision: u64,
+    /// The version as written
+    text: String,
+}
+
+/// Compare two strings of digits as integers, whatever their size
+fn compare_integers(a: &str, b: &str) -> Ordering {
+    let a = a.trim_start_matches('0');
+    let b = b.trim_start_matches('0');
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Parse the digits at the start of `s`, and return them with the rest of `s`
+fn split_digits(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+impl Version {
+    /// Return the revision of the version (`-r1`), 0 if there is none
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Return true if both versions are the same, without looking at the revision
+    pub fn same_upstream(&self, other: &Version) -> bool {
+        self.cmp_without_revision(other) == Ordering::Equal
+    }
+
+    /// Compare the numeric components with the PMS algorithm
+    fn cmp_numbers(&self, other: &Version) -> Ordering {
+        let first = compare_integers(&self.numbers[0], &other.numbers[0]);
+        if first != Ordering::Equal {
+            return first;
+        }
+
+        for (a, b) in self.numbers.iter().zip(&other.numbers).skip(1) {
+            let result = if a.starts_with('0') || b.starts_with('0') {
+                // Compared as decimals: 1.01 < 1.1
+                a.trim_end_matches('0').cmp(b.trim_end_matches('0'))
+            } else {
+                compare_integers(a, b)
+            };
+            if result != Ordering::Equal {
+                return result;
+            }
+        }
+
+        self.numbers.len().cmp(&other.numbers.len())
+    }
+
+    /// Compare the suffixes with the PMS algorithm
+    fn cmp_suffixes(&self, other: &Version) -> Ordering {
+        for (a, b) in self.suffixes.iter().zip(&other.suffixes) {
+            let result = a.cmp(b);
+            if result != Ordering::Equal {
+                return result;
+            }
+        }
+
+        // The version with more suffixes is greater only if its next suffix is _p
+        match self.suffixes.len().cmp(&other.suffixes.len()) {
+            Ordering::Greater if self.suffixes[other.suffixes.len()].0 == Suffix::P => {
+                Ordering::Greater
+            }
+            Ordering::Greater => Ordering::Less,
+            Ordering::Less if other.suffixes[self.suffixes.len()].0 == Suffix::P => Ordering::Less,
+            Ordering::Less => Ordering::Greater,
+            Ordering::Equal => Ordering::Equal,
+        }
+    }
+
+    fn cmp_without_revision(&self, other: &Version) -> Ordering {
+        self.cmp_numbers(other)
+            .then_with(|| self.letter.cmp(&other.letter))
+            .then_with(|| self.cmp_suffixes(other))
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    /// Parse a version. Return an error if it does not follow the PMS syntax
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid version '{s}'");
+
+        let (mut rest, revision) = match s.rsplit_once("-r") {
+            Some((main, r)) if !r.is_empty() && r.bytes().all(|c| c.is_ascii_digit()) => {
+                (main, r.parse().map_err(|_| error())?)
+            }
+            _ => (s, 0),
+        };
+
+        let mut numbers = Vec::new();
+        loop {
+            let (digits, after) = split_digits(rest);
+            if digits.is_empty() {
+                return Err(error());
+            }
+            numbers.push(digits.to_string());
+            rest = after;
+            match rest.strip_prefix('.') {
+                Some(after) => rest = after,
+                None => break,
+            }
+        }
+
+        let mut letter = None;
+        if let Some(c) = rest.chars().next().filter(char::is_ascii_lowercase) {
+            letter = Some(c);
+            rest = &rest[1..];
+        }
+
+        let mut suffixes = Vec::new();
+        while let Some(after) = rest.strip_prefix('_') {
+            let (name, suffix) = Suffix::NAMES
+                .iter()
+                .find(|(name, _)| after.starts_with(name))
+                .ok_or_else(error)?;
+            let (digits, after) = split_digits(&after[name.len()..]);
+            let number = if digits.is_empty() {
+                0
+            } else {
+                digits.parse().map_err(|_| error())?
+            };
+            suffixes.push((*suffix, number));
+            rest = after;
+        }
+
+        if !rest.is_empty() {
+            return Err(error());
+        }
+
+        Ok(Version {
+            numbers,
+            letter,
+            suffixes,
+            revision,
+            text: s.to_string(),
+        })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_without_revision(other)
+            .then_with(|| self.revision.cmp(&other.revision))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// A package with its version: `category/name-version`
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cpv {
+    /// The category of the package
+    pub category: String,
+    /// The name of the package
+    pub name: String,
+    /// The version of the package
+    pub version: Version,
+}
+
+impl Cpv {
+    /// Return the category/name representation of the package
+    pub fn cpn(&self) -> String {
+        format!("{}/{}", self.category, self.name)
+    }
+}
+
+impl FromStr for Cpv {
+    type Err = String;
+
+    /// Parse `category/name-version`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cpn, version) = split_cpv(s).ok_or(format!("no version in '{s}'"))?;
+        let (category, name) = cpn.split_once('/').ok_or(format!("no category in '{s}'"))?;
+        if category.is_empty() {
+            return Err(format!("no category in '{s}'"));
+        }
+
+        Ok(Cpv {
+            category: category.to_string(),
+            name: name.to_string(),
+            version: version.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for Cpv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}-{}", self.category, self.name, self.version)
+    }
+}
+
+/// Split `category/name-version` in `category/name` and `version`
+///
+/// The version starts after the first `-` followed by a valid version (see [`Version`]),
+/// so that names like `foo-2bar` or `libfoo-3d` are kept whole.
+/// Return `None` if there is no version.
+pub fn split_cpv(cpv: &str) -> Option<(&str, &str)> {
+    let name_start = cpv.find('/').map(|i| i + 1).unwrap_or(0);
+    cpv.char_indices()
+        .skip(name_start + 1)
+        .filter(|(_, c)| *c == '-')
+        .map(|(i, _)| (&cpv[..i], &cpv[i + 1..]))
+        .find(|(_, version)| version.parse::<Version>().is_ok())
+}
+
+/// Return the size of `category/name` in `category/name-version`, or the size of `cpv` if there is no version
+pub fn cpn_len(cpv: &str) -> usize {
+    match split_cpv(cpv) {
+        Some((cpn, _)) => cpn.len(),
+        None => cpv.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> Version {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn version_valid() {
+        for s in [
+            "1",
+            "1.2.3",
+            "1.2b",
+            "1_alpha",
+            "1.0_rc2_p3",
+            "2024.10-r12",
+            "1a_pre",
+        ] {
+            assert!(s.parse::<Version>().is_ok(), "{s}");
+        }
+        for s in [
+            "", "a", "1.", ".1", "1ab", "1_gamma", "1-r", "1-rc", "1.2-3",
+        ] {
+            assert!(s.parse::<Version>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn version_order() {
+        let ordered = [
+            "0.9",
+            "1.0_alpha",
+            "1.0_alpha1",
+            "1.0_beta",
+            "1.0_pre2",
+            "1.0_rc1",
+            "1.0_rc1_p1",
+            "1.0",
+            "1.0-r1",
+            "1.0-r10",
+            "1.0_p1",
+            "1.0a",
+            "1.0b",
+            "1.0.1",
+            "1.01.2",
+            "1.1",
+            "1.10",
+            "2",
+        ];
+        for w in ordered.windows(2) {
+            assert!(v(w[0]) < v(w[1]), "{} < {}", w[0], w[1]);
+        }
+    }
+
+    #[test]
+    fn version_equal() {
+        assert_eq!(v("1.0"), v("1.0-r0"));
+        assert_eq!(v("1.0"), v("1.00"));
+        assert_eq!(v("01"), v("1"));
+        assert!(v("1.0-r1").same_upstream(&v("1.0-r2")));
+        assert_eq!(v("1.0-r2").revision(), 2);
+        assert_eq!(v("1.0_p1").to_string(), "1.0_p1");
+    }
+
+    #[test]
+    fn cpv_parse() {
+        let cpv: Cpv = "dev-python/PyQt6-6.7.1-r1".parse().unwrap();
+        assert_eq!(cpv.category, "dev-python");
+        assert_eq!(cpv.name, "PyQt6");
+        assert_eq!(cpv.version, v("6.7.1-r1"));
+        assert_eq!(cpv.to_string(), "dev-python/PyQt6-6.7.1-r1");
+        assert!("dev-python/PyQt6".parse::<Cpv>().is_err());
+        assert!("PyQt6-6.7.1".parse::<Cpv>().is_err());
+    }
+
+    #[test]
+    fn split_cpv_names_with_digits() {
+        assert_eq!(split_cpv("a/foo-2bar-1.0"), Some(("a/foo-2bar", "1.0")));
+        assert_eq!(
+            split_cpv("dev-libs/libfoo-3d-1.0"),
+            Some(("dev-libs/libfoo-3d", "1.0"))
+        );
+        assert_eq!(split_cpv("a/b-1-r1"), Some(("a/b", "1-r1")));
+        assert_eq!(split_cpv("sys-devel/gcc"), None);
+    }
+
+    #[test]
+    fn cpn_len_simple() {
+        assert_eq!(cpn_len("hello"), 5);
+        assert_eq!(cpn_len("sys-devel/gcc"), "sys-devel/gcc".len());
+        assert_eq!(cpn_len("sys-devel/gcc-12.4.0"), "sys-devel/gcc".len());
+        assert_eq!(cpn_len("dev-python/PyQt6-6.7.1-r1"), 16);
+    }
+}
@@ -0,0 +1,309 @@
+#![warn(missing_docs)]
+
+//! Measure how well the emerge times would have been predicted, by replaying the history
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::collections::BTreeMap;
+
+use crate::{
+    calibrate::median,
+    clock::FixedClock,
+    package::{Atom, Merge},
+    parse_file::LogState,
+    useful::{format_duration, Over},
+};
+
+/// A way to predict the time of an emerge from the previous emerges of the package
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Estimator {
+    /// What gls prints when the emerge starts: the filtered average, plus 25% and a minute (see [`Atom::comp_avg`])
+    Gls,
+    /// The average without the best and the worst times (see [`Atom::filter_time`])
+    Filtered,
+    /// The average of all the times
+    Average,
+    /// The worst time
+    Worst,
+    /// The time of the last emerge
+    Last,
+}
+
+impl Estimator {
+    /// All the estimators, in the order of the report
+    pub const ALL: [Estimator; 5] = [
+        Estimator::Gls,
+        Estimator::Filtered,
+        Estimator::Average,
+        Estimator::Worst,
+        Estimator::Last,
+    ];
+
+    /// Return the name of the estimator, as shown in the report
+    pub fn name(&self) -> &'static str {
+        match self {
+            Estimator::Gls => "gls",
+            Estimator::Filtered => "filtered",
+            Estimator::Average => "average",
+            Estimator::Worst => "worst",
+            Estimator::Last => "last",
+        }
+    }
+
+    /// Return the time predicted for the next emerge of `atom`
+    pub fn predict(&self, atom: &Atom) -> f64 {
+        match self {
+            // At the start of the emerge, no time has passed since `last_time`
+            Estimator::Gls => atom.comp_avg(&FixedClock(atom.last_time), &mut Over::NO),
+            Estimator::Filtered => atom.filter_time(),
+            Estimator::Average => atom.time_avg(),
+            Estimator::Worst => atom.worst_time as f64,
+            Estimator::Last => atom
+                .versions
+                .last()
+                .map_or(atom.time_avg(), |(_, time)| *time as f64),
+        }
+    }
+}
+
+/// The errors of the predictions of an estimator
+#[derive(Default)]
+pub struct Accuracy {
+    /// The absolute errors, in seconds
+    errors: Vec<f64>,
+    /// The absolute errors, in percentage of the real time (the emerges that took no time are left out)
+    percentages: Vec<f64>,
+    /// The number of predictions shorter than the real time
+    under: usize,
+}
+
+impl Accuracy {
+    /// Record a prediction and the real time of the emerge
+    pub fn add(&mut self, predicted: f64, real: u32) {
+        let real = real as f64;
+        let error = (predicted - real).abs();
+        self.errors.push(error);
+        if real > 0.0 {
+            self.percentages.push(error / real * 100.0);
+        }
+        if predicted < real {
+            self.under += 1;
+        }
+    }
+
+    /// Return the number of predictions
+    pub fn count(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Return the mean absolute error, in seconds
+    pub fn mean_error(&self) -> f64 {
+        if self.errors.is_empty() {
+            return 0.0;
+        }
+        self.errors.iter().sum::<f64>() / self.errors.len() as f64
+    }
+
+    /// Return the median absolute percentage error, or `None` if every emerge took no time
+    pub fn median_percentage(&self) -> Option<f64> {
+        if self.percentages.is_empty() {
+            return None;
+        }
+        Some(median(&mut self.percentages.clone()))
+    }
+
+    /// Return the percentage of predictions shorter than the real time
+    pub fn under_percentage(&self) -> f64 {
+        if self.errors.is_empty() {
+            return 0.0;
+        }
+        self.under as f64 * 100.0 / self.errors.len() as f64
+    }
+
+    /// Return a line with the statistics of the estimator, for `gls backtest`
+    ///
+    /// # Examples
+    /// `gls       MAE 4m, median error 35%, 12% under-predicted`
+    pub fn line(&self, estimator: Estimator) -> String {
+        let median = self
+            .median_percentage()
+            .map_or("?".to_string(), |p| format!("{p:.0}%"));
+        format!(
+            "{:<9} MAE {}, median error {median}, {:.0}% under-predicted",
+            estimator.name(),
+            format_duration(self.mean_error() as u32),
+            self.under_percentage()
+        )
+    }
+}
+
+/// The accuracy of every estimator (in the order of [`Estimator::ALL`]), for all the packages and for each of them
+#[derive(Default)]
+pub struct Backtest {
+    /// The accuracy on all the predictions
+    pub overall: Vec<Accuracy>,
+    /// The accuracy on the predictions of each package, by key of history
+    pub packages: BTreeMap<String, Vec<Accuracy>>,
+}
+
+/// Return the atom with only the emerges of the slot of `merge`, if it is known
+fn in_slot(atom: &Atom, merge: &Merge) -> Option<Atom> {
+    let slot = if merge.slot.is_empty() {
+        atom.slot_of(&merge.version)?
+    } else {
+        &merge.slot
+    };
+    atom.for_slot(slot)
+}
+
+/// Predict each merge with every estimator, using only the merges done before it
+///
+/// * `merges`: All the merges of a root, in the order of the log
+/// * `keep`: The merges to predict (the others still count as history)
+///
+/// Binary merges, and the first merge of each package, cannot be predicted and are left out.
+/// As in the status, only the times of the slot of the package are used when it is known.
+pub fn backtest(merges: &[Merge], keep: impl Fn(&Merge) -> bool) -> Backtest {
+    let new_accuracies = || Estimator::ALL.iter().map(|_| Accuracy::default()).collect();
+    let mut result = Backtest {
+        overall: new_accuracies(),
+        packages: BTreeMap::new(),
+    };
+    let mut history = LogState::default();
+
+    for merge in merges {
+        let key = merge.key();
+        if let Some(atom) = history.completed_atoms.get(&key) {
+            if !merge.is_binary && keep(merge) {
+                let local = in_slot(atom, merge);
+                let atom = local.as_ref().unwrap_or(atom);
+                let package = result.packages.entry(key).or_insert_with(new_accuracies);
+                for (i, estimator) in Estimator::ALL.iter().enumerate() {
+                    let predicted = estimator.predict(atom);
+                    result.overall[i].add(predicted, merge.duration);
+                    package[i].add(predicted, merge.duration);
+                }
+            }
+        }
+        history.add_merge(merge.clone());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(cpn: &str, version: &str, duration: u32) -> Merge {
+        Merge {
+            time: 0,
+            cpn: cpn.to_string(),
+            version: version.to_string(),
+            is_binary: false,
+            duration,
+            num: "1 of 1".to_string(),
+            session: 1,
+            target_root: "/".to_string(),
+            repository: String::new(),
+            slot: String::new(),
+        }
+    }
+
+    #[test]
+    fn estimators_predict() {
+        let mut atom = Atom::new("cat/pkg".to_string(), 100, 0);
+        atom.versions.push(("cat/pkg-1".to_string(), 100));
+        for time in [300, 200] {
+            atom.add(time);
+            atom.versions.push(("cat/pkg-1".to_string(), time));
+        }
+
+        assert_eq!(Estimator::Filtered.predict(&atom), 200.0);
+        assert_eq!(Estimator::Gls.predict(&atom), 310.0);
+        assert_eq!(Estimator::Average.predict(&atom), 200.0);
+        assert_eq!(Estimator::Worst.predict(&atom), 300.0);
+        assert_eq!(Estimator::Last.predict(&atom), 200.0);
+    }
+
+    #[test]
+    fn accuracy_stats() {
+        let mut accuracy = Accuracy::default();
+        assert_eq!(accuracy.median_percentage(), None);
+        accuracy.add(90.0, 100);
+        accuracy.add(120.0, 100);
+        accuracy.add(100.0, 200);
+
+        assert_eq!(accuracy.count(), 3);
+        assert_eq!(accuracy.mean_error(), 130.0 / 3.0);
+        assert_eq!(accuracy.median_percentage(), Some(20.0));
+        assert_eq!(accuracy.under_percentage(), 200.0 / 3.0);
+        assert_eq!(
+            accuracy.line(Estimator::Last),
+            "last      MAE 43s, median error 20%, 67% under-predicted"
+        );
+    }
+
+    #[test]
+    fn backtest_prior_only() {
+        let merges = vec![
+            merge("cat/a", "1", 100),
+            merge("cat/b", "1", 50),
+            merge("cat/a", "2", 200),
+            Merge {
+                is_binary: true,
+                ..merge("cat/a", "2", 10)
+            },
+            merge("cat/a", "3", 150),
+        ];
+        let result = backtest(&merges, |_| true);
+
+        // The first emerge of each package and the binary one are not predicted
+        assert_eq!(result.overall[0].count(), 2);
+        assert_eq!(result.packages.len(), 1);
+        // The last time is 100 for the second emerge, then 200 for the third
+        let last = &result.packages["cat/a"][4];
+        assert_eq!(last.mean_error(), 75.0);
+        assert_eq!(last.under_percentage(), 50.0);
+
+        let only_b = backtest(&merges, |m| m.cpn == "cat/b");
+        assert_eq!(only_b.overall[0].count(), 0);
+        assert!(only_b.packages.is_empty());
+    }
+
+    #[test]
+    fn backtest_slots() {
+        let merges = vec![
+            Merge {
+                slot: "15".to_string(),
+                ..merge("sys-devel/llvm", "15.0.7", 1000)
+            },
+            Merge {
+                slot: "18".to_string(),
+                ..merge("sys-devel/llvm", "18.1.6", 3000)
+            },
+            merge("sys-devel/llvm", "18.1.7", 3000),
+        ];
+        let result = backtest(&merges, |m| m.version == "18.1.7");
+
+        // Only the time of the slot 18 is used
+        assert_eq!(result.overall[2].mean_error(), 0.0);
+    }
+}
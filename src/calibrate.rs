@@ -0,0 +1,240 @@
+#![warn(missing_docs)]
+
+//! Compute the relative speed of two machines from their histories
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::collections::HashMap;
+
+use crate::package::Atom;
+
+/// The ratios further than this number of (scaled) median absolute deviation from the median are rejected
+const OUTLIER_THRESHOLD: f64 = 3.0;
+
+/// The result of the comparison between the local history and the one of a reference
+pub struct Calibration {
+    /// How much time the local machine needs compared to the reference (see [`ReferenceSource::factor`](crate::reference::ReferenceSource::factor))
+    pub factor: f64,
+    /// The number of ratios used to compute the factor
+    pub compared: usize,
+    /// The number of ratios rejected as outliers
+    pub rejected: usize,
+}
+
+impl Calibration {
+    /// Return a sentence describing the speed of `reference` compared to `local`
+    pub fn describe(&self, reference: &str, local: &str) -> String {
+        let speed = if self.factor >= 1.0 {
+            format!("{:.1}x faster than", self.factor)
+        } else {
+            format!("{:.1}x slower than", 1.0 / self.factor)
+        };
+        format!(
+            "{reference} is {speed} {local} (factor={:.2}, {} comparisons, {} outliers rejected)",
+            self.factor, self.compared, self.rejected
+        )
+    }
+}
+
+/// Return the median of `values`. They are sorted in the process
+pub fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    if n.is_multiple_of(2) {
+        (values[n / 2 - 1] + values[n / 2]) / 2.0
+    } else {
+        values[n / 2]
+    }
+}
+
+/// Return the median time of the emerges of `full_name` in `atom`
+fn median_version(atom: &Atom, full_name: &str) -> Option<f64> {
+    let mut times: Vec<f64> = atom
+        .versions
+        .iter()
+        .filter(|(name, _)| name == full_name)
+        .map(|(_, time)| *time as f64)
+        .collect();
+    if times.is_empty() {
+        return None;
+    }
+    Some(median(&mut times))
+}
+
+/// Return the ratios local/reference of the times of a package
+///
+/// One ratio is given for each version emerged on both machines.
+/// If there is none, the averages of the package are compared.
+fn ratios(local: &Atom, reference: &Atom) -> Vec<f64> {
+    let mut seen: Vec<&str> = Vec::new();
+    let mut result = Vec::new();
+    for (full_name, _) in &local.versions {
+        if seen.contains(&full_name.as_str()) {
+            continue;
+        }
+        seen.push(full_name);
+        if let (Some(l), Some(r)) = (
+            median_version(local, full_name),
+            median_version(reference, full_name),
+        ) {
+            result.push((l, r));
+        }
+    }
+
+    if result.is_empty() {
+        result.push((
+            local.total_time as f64 / local.num_emerge as f64,
+            reference.total_time as f64 / reference.num_emerge as f64,
+        ));
+    }
+
+    result
+        .into_iter()
+        .filter(|(l, r)| (*l > 0.0) && (*r > 0.0))
+        .map(|(l, r)| l / r)
+        .collect()
+}
+
+/// Compute how much time the local machine needs compared to the reference
+///
+/// * `local`: The atoms of the local machine
+/// * `reference`: The atoms of the other machine
+///
+/// The ratios are compared in log space, and the ones too far from the median are rejected.
+/// The factor is the geometric mean of the remaining ones.
+/// Return `None` if the two histories have no package in common.
+pub fn calibrate(
+    local: &HashMap<String, Atom>,
+    reference: &HashMap<String, Atom>,
+) -> Option<Calibration> {
+    let mut logs: Vec<f64> = local
+        .iter()
+        .filter_map(|(cpn, atom)| reference.get(cpn).map(|r| ratios(atom, r)))
+        .flatten()
+        .map(f64::ln)
+        .collect();
+    if logs.is_empty() {
+        return None;
+    }
+
+    let center = median(&mut logs);
+    let mut deviations: Vec<f64> = logs.iter().map(|l| (l - center).abs()).collect();
+    // 1.4826 makes the median absolute deviation comparable to a standard deviation
+    let limit = OUTLIER_THRESHOLD * 1.4826 * median(&mut deviations);
+
+    let kept: Vec<f64> = logs
+        .iter()
+        .copied()
+        .filter(|l| (l - center).abs() <= limit)
+        .collect();
+    let mean = kept.iter().sum::<f64>() / kept.len() as f64;
+
+    Some(Calibration {
+        factor: mean.exp(),
+        compared: kept.len(),
+        rejected: logs.len() - kept.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_atom(cpn: &str, times: &[(&str, u32)]) -> Atom {
+        let mut atom = Atom::new(cpn.to_string(), times[0].1, 0);
+        for (_, time) in &times[1..] {
+            atom.add(*time);
+        }
+        atom.versions = times.iter().map(|(n, t)| (n.to_string(), *t)).collect();
+        atom
+    }
+
+    fn create_history(atoms: Vec<Atom>) -> HashMap<String, Atom> {
+        atoms.into_iter().map(|a| (a.cpn.clone(), a)).collect()
+    }
+
+    #[test]
+    fn median_values() {
+        assert_eq!(median(&mut [3.0, 1.0, 2.0]), 2.0);
+        assert_eq!(median(&mut [4.0, 1.0, 2.0, 3.0]), 2.5);
+    }
+
+    #[test]
+    fn ratios_same_version() {
+        let local = create_atom("a/b", &[("a/b-1", 100), ("a/b-2", 300), ("a/b-2", 500)]);
+        let reference = create_atom("a/b", &[("a/b-2", 200), ("a/b-3", 10)]);
+        assert_eq!(ratios(&local, &reference), vec![2.0]);
+    }
+
+    #[test]
+    fn ratios_no_common_version() {
+        let local = create_atom("a/b", &[("a/b-1", 100), ("a/b-1", 300)]);
+        let reference = create_atom("a/b", &[("a/b-2", 50)]);
+        assert_eq!(ratios(&local, &reference), vec![4.0]);
+    }
+
+    #[test]
+    fn calibrate_nothing_in_common() {
+        let local = create_history(vec![create_atom("a/b", &[("a/b-1", 100)])]);
+        let reference = create_history(vec![create_atom("a/c", &[("a/c-1", 100)])]);
+        assert!(calibrate(&local, &reference).is_none());
+    }
+
+    #[test]
+    fn calibrate_with_outlier() {
+        let local = create_history(vec![
+            create_atom("a/b", &[("a/b-1", 200)]),
+            create_atom("a/c", &[("a/c-1", 400)]),
+            create_atom("a/d", &[("a/d-1", 220)]),
+            create_atom("a/e", &[("a/e-1", 9000)]),
+        ]);
+        let reference = create_history(vec![
+            create_atom("a/b", &[("a/b-1", 100)]),
+            create_atom("a/c", &[("a/c-1", 200)]),
+            create_atom("a/d", &[("a/d-1", 100)]),
+            create_atom("a/e", &[("a/e-1", 100)]),
+        ]);
+        let calibration = calibrate(&local, &reference).unwrap();
+        assert_eq!(calibration.compared, 3);
+        assert_eq!(calibration.rejected, 1);
+        assert!((calibration.factor - 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn calibration_describe() {
+        let calibration = Calibration {
+            factor: 2.7,
+            compared: 10,
+            rejected: 1,
+        };
+        assert_eq!(
+            calibration.describe("buildbox", "laptop"),
+            "buildbox is 2.7x faster than laptop (factor=2.70, 10 comparisons, 1 outliers rejected)"
+        );
+        let calibration = Calibration {
+            factor: 0.5,
+            compared: 1,
+            rejected: 0,
+        };
+        assert!(calibration
+            .describe("old", "laptop")
+            .starts_with("old is 2.0x slower than laptop"));
+    }
+}
@@ -0,0 +1,66 @@
+#![warn(missing_docs)]
+
+//! The clock giving the current time, that can be fixed to replay a log
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use crate::useful::current_time;
+
+/// Give the current time, as the number of seconds since EPOCH
+///
+/// Everything computing an elapsed time takes a clock, so that the time can be fixed (see [`FixedClock`]).
+pub trait Clock {
+    /// Return the current time
+    fn now(&self) -> u32;
+}
+
+/// The clock of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        current_time() as u32
+    }
+}
+
+/// A clock always giving the same time, to show what would have been printed at this time (`--now`) or for the tests
+pub struct FixedClock(pub u32);
+
+impl Clock for FixedClock {
+    fn now(&self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_clock() {
+        assert_eq!(FixedClock(1234567890).now(), 1234567890);
+    }
+
+    #[test]
+    fn system_clock() {
+        // 2024-01-01
+        assert!(SystemClock.now() > 1704067200);
+    }
+}
@@ -0,0 +1,298 @@
+#![warn(missing_docs)]
+
+//! Export the completed merges to CSV or JSON Lines, and read them back
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::error::Error;
+
+use clap::ValueEnum;
+use serde_json::{json, Value};
+
+use crate::{
+    logfile::{for_each_line, open_log},
+    package::Merge,
+};
+
+/// The columns of the CSV export, in order
+const CSV_HEADER: &str =
+    "timestamp,cpn,version,binary,duration,root,file,num,session,target_root,repository,slot";
+
+/// The formats we can export to
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum ExportFormat {
+    /// Comma separated values, with a header
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl ExportFormat {
+    /// Return the format of an exported file from its extension
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path.ends_with(".csv") {
+            Some(Self::Csv)
+        } else if path.ends_with(".jsonl") {
+            Some(Self::Jsonl)
+        } else {
+            None
+        }
+    }
+
+    /// Return what to write before the first merge (with a newline), if anything
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Self::Csv => Some(format!("{CSV_HEADER}\n")),
+            Self::Jsonl => None,
+        }
+    }
+
+    /// Return the line (with a newline) representing `merge`
+    ///
+    /// * `merge`: The merge to export
+    /// * `root`: The name of the root the merge was done in
+    /// * `file`: The log the merge was read from
+    pub fn format(&self, merge: &Merge, root: &str, file: &str) -> String {
+        match self {
+            Self::Csv => format!(
+                "{},{},{},{},{},{},{},{},{},{},{},{}\n",
+                merge.time,
+                csv_escape(&merge.cpn),
+                csv_escape(&merge.version),
+                merge.is_binary,
+                merge.duration,
+                csv_escape(root),
+                csv_escape(file),
+                csv_escape(&merge.num),
+                merge.session,
+                csv_escape(&merge.target_root),
+                csv_escape(&merge.repository),
+                csv_escape(&merge.slot)
+            ),
+            Self::Jsonl => format!(
+                "{}\n",
+                json!({
+                    "timestamp": merge.time,
+                    "cpn": merge.cpn,
+                    "version": merge.version,
+                    "binary": merge.is_binary,
+                    "duration": merge.duration,
+                    "root": root,
+                    "file": file,
+                    "num": merge.num,
+                    "session": merge.session,
+                    "target_root": merge.target_root,
+                    "repository": merge.repository,
+                    "slot": merge.slot,
+                })
+            ),
+        }
+    }
+}
+
+/// Put `field` between quotes if it contains a comma or a quote
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Split a CSV line in its fields, removing the quotes added by [`csv_escape`]
+fn csv_split(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// Create a merge from the fields of a CSV line, that must have all the columns of [`CSV_HEADER`]
+fn merge_from_csv(fields: &[String]) -> Option<Merge> {
+    if fields.len() != CSV_HEADER.split(',').count() {
+        return None;
+    }
+    Some(Merge {
+        time: fields[0].parse().ok()?,
+        cpn: fields[1].clone(),
+        version: fields[2].clone(),
+        is_binary: fields[3].parse().ok()?,
+        duration: fields[4].parse().ok()?,
+        num: fields[7].clone(),
+        session: fields[8].parse().ok()?,
+        target_root: fields[9].clone(),
+        repository: fields[10].clone(),
+        slot: fields[11].clone(),
+    })
+}
+
+/// Create a merge from a JSON object, that must have all the columns of [`CSV_HEADER`] as keys
+fn merge_from_json(value: &Value) -> Option<Merge> {
+    if CSV_HEADER.split(',').any(|key| value.get(key).is_none()) {
+        return None;
+    }
+    Some(Merge {
+        time: value["timestamp"].as_u64()? as u32,
+        cpn: value["cpn"].as_str()?.to_string(),
+        version: value["version"].as_str()?.to_string(),
+        is_binary: value["binary"].as_bool()?,
+        duration: value["duration"].as_u64()? as u32,
+        num: value["num"].as_str()?.to_string(),
+        session: value["session"].as_u64()? as u32,
+        target_root: value["target_root"].as_str()?.to_string(),
+        repository: value["repository"].as_str()?.to_string(),
+        slot: value["slot"].as_str()?.to_string(),
+    })
+}
+
+/// Read a file created by `gls export`
+///
+/// * `path`: The file to read
+/// * `format`: The format of the file
+///
+/// The file is read line by line, and can be compressed (see [`open_log`]).
+/// Return an error if the file could not be read or if a line is not a valid merge (all the columns are required).
+pub fn read_export(path: &str, format: ExportFormat) -> Result<Vec<Merge>, Box<dyn Error>> {
+    let mut merges = Vec::new();
+    let mut invalid: Option<Box<dyn Error>> = None;
+    let mut n = 0;
+
+    for_each_line(open_log(path)?, |line| {
+        n += 1;
+        if invalid.is_some() || line.is_empty() || line.starts_with("timestamp,") {
+            return;
+        }
+        let merge = match format {
+            ExportFormat::Csv => Ok(merge_from_csv(&csv_split(line))),
+            ExportFormat::Jsonl => serde_json::from_str(line).map(|value| merge_from_json(&value)),
+        };
+        match merge {
+            Ok(Some(merge)) => merges.push(merge),
+            Ok(None) => invalid = Some(format!("invalid merge at line {n}").into()),
+            Err(e) => invalid = Some(e.into()),
+        }
+    })?;
+
+    match invalid {
+        Some(e) => Err(e),
+        None => Ok(merges),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_merge() -> Merge {
+        Merge {
+            time: 1234567800,
+            cpn: "category/package".to_string(),
+            version: "1.2.3-r1".to_string(),
+            is_binary: false,
+            duration: 11,
+            num: "1 of 2".to_string(),
+            session: 3,
+            target_root: "/".to_string(),
+            repository: "gentoo".to_string(),
+            slot: String::new(),
+        }
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert!(matches!(
+            ExportFormat::from_path("a.csv"),
+            Some(ExportFormat::Csv)
+        ));
+        assert!(matches!(
+            ExportFormat::from_path("a.jsonl"),
+            Some(ExportFormat::Jsonl)
+        ));
+        assert!(ExportFormat::from_path("emerge.log").is_none());
+    }
+
+    #[test]
+    fn export_csv() {
+        let line = ExportFormat::Csv.format(&create_merge(), "arm", "/a,b.log");
+        assert_eq!(
+            line,
+            "1234567800,category/package,1.2.3-r1,false,11,arm,\"/a,b.log\",1 of 2,3,/,gentoo,\n"
+        );
+    }
+
+    #[test]
+    fn export_jsonl() {
+        let line = ExportFormat::Jsonl.format(&create_merge(), "arm", "/a.log");
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["root"], "arm");
+        assert_eq!(value["file"], "/a.log");
+        assert_eq!(merge_from_json(&value).unwrap(), create_merge());
+    }
+
+    #[test]
+    fn all_columns_required() {
+        let line = ExportFormat::Jsonl.format(&create_merge(), "arm", "/a.log");
+        let mut value: Value = serde_json::from_str(&line).unwrap();
+        value.as_object_mut().unwrap().remove("slot");
+        assert!(merge_from_json(&value).is_none());
+
+        let line = ExportFormat::Csv.format(&create_merge(), "arm", "/a.log");
+        let fields = csv_split(line.trim_end());
+        assert!(merge_from_csv(&fields[..11]).is_none());
+    }
+
+    #[test]
+    fn csv_round_trip() {
+        assert_eq!(csv_split("a,\"b,\"\"c\"\"\",d"), vec!["a", "b,\"c\"", "d"]);
+        let line = ExportFormat::Csv.format(&create_merge(), "a\"b", "/a,b.log");
+        let fields = csv_split(line.trim_end());
+        assert_eq!(fields[5], "a\"b");
+        assert_eq!(merge_from_csv(&fields).unwrap(), create_merge());
+        assert!(merge_from_csv(&fields[..9]).is_none());
+    }
+
+    #[test]
+    fn read_export_both() {
+        let csv = read_export("./tests/export/two.csv", ExportFormat::Csv).unwrap();
+        let jsonl = read_export("./tests/export/two.jsonl", ExportFormat::Jsonl).unwrap();
+        assert_eq!(csv.len(), 2);
+        assert_eq!(csv, jsonl);
+        assert_eq!(csv[1].cpn, "category/package2");
+        assert_eq!(csv[1].slot, "0");
+    }
+
+    #[test]
+    fn read_export_invalid() {
+        assert!(read_export("./tests/do_not_exist.csv", ExportFormat::Csv).is_err());
+        assert!(read_export("./tests/emerge.log/binary_running", ExportFormat::Csv).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+#![warn(missing_docs)]
+
+//! The lifecycle of the packages: when they were installed, upgraded and removed
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::collections::HashMap;
+
+use crate::{
+    package::{cpn_is_about, Merge, MergeKind, Unmerge},
+    session::format_date,
+    useful::format_duration,
+};
+
+/// What happened to a package
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// The package was merged, compared to the version installed before (if any)
+    Merged(MergeKind),
+    /// The package was unmerged by the user (`--unmerge`, `--depclean`)
+    Removed,
+    /// The package was unmerged by emerge after the merge of another version
+    Cleaned,
+}
+
+/// One line of the history of a package
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// When the event started (the start of the merge or of the unmerge)
+    pub time: u32,
+    /// The category/package_name representation of the package
+    pub cpn: String,
+    /// The version of the package
+    pub version: String,
+    /// What happened
+    pub event: Event,
+    /// How long it took
+    pub duration: u32,
+    /// Was it a binary merge ?
+    pub is_binary: bool,
+    /// The repository of the package (the one of the last merge if unknown)
+    pub repository: String,
+}
+
+impl HistoryEntry {
+    /// Return true if the entry is about `package` (see [`cpn_is_about`])
+    pub fn is_about(&self, package: &str) -> bool {
+        cpn_is_about(&self.cpn, package)
+    }
+
+    /// Return the line shown by `gls history`
+    ///
+    /// # Examples
+    /// `2024-09-30 14:00:03  sys-devel/gcc-14.2.1  upgraded from 13.3.1 (1h 2m)`
+    pub fn line(&self) -> String {
+        let event = match &self.event {
+            Event::Merged(MergeKind::New) => "installed".to_string(),
+            Event::Merged(MergeKind::Upgrade(from)) => format!("upgraded from {from}"),
+            Event::Merged(MergeKind::Downgrade(from)) => format!("downgraded from {from}"),
+            Event::Merged(MergeKind::Rebuild) => "rebuilt".to_string(),
+            Event::Removed => "removed".to_string(),
+            Event::Cleaned => "cleaned".to_string(),
+        };
+        let kind = if self.is_binary { "binary, " } else { "" };
+        format!(
+            "{}  {}-{}  {event} ({kind}{})",
+            format_date(self.time),
+            self.cpn,
+            self.version,
+            format_duration(self.duration)
+        )
+    }
+}
+
+/// The version installed of each package, by cpn and target root, to classify its next merge
+///
+/// It is shared by [`history`] and the live view, so that both give the same [`MergeKind`].
+#[derive(Default)]
+pub struct Installed<'a> {
+    /// The version installed (none if the package was removed), by (cpn, target root)
+    versions: HashMap<(&'a str, &'a str), Option<&'a str>>,
+}
+
+impl<'a> Installed<'a> {
+    /// Return the versions installed after `merges` and `unmerges`
+    pub fn new(merges: &'a [Merge], unmerges: &'a [Unmerge]) -> Self {
+        let mut installed = Installed::default();
+        for (is_unmerge, i) in in_order(merges, unmerges) {
+            if is_unmerge {
+                installed.unmerge(&unmerges[i]);
+            } else {
+                installed.merge(&merges[i]);
+            }
+        }
+        installed
+    }
+
+    /// Compare `version` of `cpn` merged to `target_root` with the version installed
+    ///
+    /// Return `None` if no merge or unmerge of the package was recorded.
+    pub fn classify(&self, cpn: &str, target_root: &str, version: &str) -> Option<MergeKind> {
+        self.versions
+            .get(&(cpn, target_root))
+            .map(|installed| MergeKind::classify(*installed, version))
+    }
+
+    /// Record `merge`, which replaces the version installed before, and return how it changed it
+    pub fn merge(&mut self, merge: &'a Merge) -> MergeKind {
+        let installed = self
+            .versions
+            .entry((&merge.cpn, &merge.target_root))
+            .or_default();
+        let kind = MergeKind::classify(*installed, &merge.version);
+        *installed = Some(&merge.version);
+        kind
+    }
+
+    /// Record `unmerge`
+    ///
+    /// The versions cleaned by emerge were already replaced by the merge of the new ones.
+    /// As the log does not give the target root of the unmerges, a removed version is removed from all the roots.
+    pub fn unmerge(&mut self, unmerge: &Unmerge) {
+        if unmerge.autoclean {
+            return;
+        }
+        for ((cpn, _), installed) in &mut self.versions {
+            if (*cpn == unmerge.cpn) && (*installed == Some(unmerge.version.as_str())) {
+                *installed = None;
+            }
+        }
+    }
+}
+
+/// Return the merges and unmerges ordered by their start, as (is an unmerge, index)
+///
+/// The start is used as emerge removes the old versions before the end of the merge.
+fn in_order(merges: &[Merge], unmerges: &[Unmerge]) -> Vec<(bool, usize)> {
+    let mut order: Vec<(u32, bool, usize)> = merges
+        .iter()
+        .enumerate()
+        .map(|(i, m)| (m.time, false, i))
+        .chain(unmerges.iter().enumerate().map(|(i, u)| (u.time, true, i)))
+        .collect();
+    order.sort();
+    order
+        .into_iter()
+        .map(|(_, is_unmerge, i)| (is_unmerge, i))
+        .collect()
+}
+
+/// Return the history of all the packages, in the order they happened
+///
+/// * `merges`: The completed merges
+/// * `unmerges`: The completed unmerges
+///
+/// The merges are classified with [`Installed`].
+pub fn history(merges: &[Merge], unmerges: &[Unmerge]) -> Vec<HistoryEntry> {
+    let mut installed = Installed::default();
+    // The last repository known, by cpn
+    let mut repositories: HashMap<&str, &str> = HashMap::new();
+    let mut entries = Vec::new();
+    for (is_unmerge, i) in in_order(merges, unmerges) {
+        if is_unmerge {
+            let u = &unmerges[i];
+            installed.unmerge(u);
+            entries.push(HistoryEntry {
+                time: u.time,
+                cpn: u.cpn.clone(),
+                version: u.version.clone(),
+                event: if u.autoclean {
+                    Event::Cleaned
+                } else {
+                    Event::Removed
+                },
+                duration: u.duration,
+                is_binary: false,
+                repository: repositories.get(u.cpn.as_str()).unwrap_or(&"").to_string(),
+            });
+        } else {
+            let m = &merges[i];
+            if !m.repository.is_empty() {
+                repositories.insert(&m.cpn, &m.repository);
+            }
+            let event = Event::Merged(installed.merge(m));
+            entries.push(HistoryEntry {
+                time: m.time,
+                cpn: m.cpn.clone(),
+                version: m.version.clone(),
+                event,
+                duration: m.duration,
+                is_binary: m.is_binary,
+                repository: repositories.get(m.cpn.as_str()).unwrap_or(&"").to_string(),
+            });
+        }
+    }
+
+    entries
+}
+
+/// Return the packages rebuilt with the same version, with the number of rebuilds and the time of the last one
+///
+/// * `entries`: The history, see [`history`]
+///
+/// The packages rebuilt the most often (for example by `@preserved-rebuild`) are first.
+pub fn rebuilds(entries: &[HistoryEntry]) -> Vec<(String, u32, u32)> {
+    let mut counts: HashMap<String, (u32, u32)> = HashMap::new();
+    for entry in entries {
+        if entry.event == Event::Merged(MergeKind::Rebuild) {
+            let count = counts
+                .entry(format!("{}-{}", entry.cpn, entry.version))
+                .or_default();
+            count.0 += 1;
+            count.1 = count.1.max(entry.time);
+        }
+    }
+
+    let mut result: Vec<(String, u32, u32)> = counts
+        .into_iter()
+        .map(|(full_name, (count, last))| (full_name, count, last))
+        .collect();
+    result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_file::read_file_test;
+
+    #[test]
+    fn history_unmerge() {
+        let state = read_file_test("./tests/emerge.log/unmerge");
+        let events: Vec<(String, Event)> = history(&state.merges, &state.unmerges)
+            .into_iter()
+            .map(|e| (format!("{}-{}", e.cpn, e.version), e.event))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "category/package-1.3".to_string(),
+                    Event::Merged(MergeKind::New)
+                ),
+                ("category/package-1.2.3".to_string(), Event::Cleaned),
+                ("category/other-2.0".to_string(), Event::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn history_repository() {
+        let state = read_file_test("./tests/emerge.log/repository");
+        let entries = history(&state.merges, &state.unmerges);
+        assert_eq!(entries[0].repository, "gentoo");
+        assert_eq!(entries[1].repository, "guru");
+        assert_eq!(
+            entries[1].event,
+            Event::Merged(MergeKind::Upgrade("1.2.3".to_string()))
+        );
+    }
+
+    #[test]
+    fn history_upgrade() {
+        let merge = |version: &str, time| Merge {
+            time,
+            cpn: "a/b".to_string(),
+            version: version.to_string(),
+            is_binary: false,
+            duration: 10,
+            num: "1 of 1".to_string(),
+            session: 1,
+            target_root: "/".to_string(),
+            repository: "gentoo".to_string(),
+            slot: String::new(),
+        };
+        let merges = [
+            merge("1", 0),
+            merge("2", 100),
+            merge("2", 200),
+            merge("1", 300),
+            merge("1", 400),
+            merge("1", 500),
+        ];
+        let entries = history(&merges, &[]);
+        assert_eq!(entries[0].event, Event::Merged(MergeKind::New));
+        assert_eq!(
+            entries[1].event,
+            Event::Merged(MergeKind::Upgrade("1".to_string()))
+        );
+        assert_eq!(entries[2].event, Event::Merged(MergeKind::Rebuild));
+        // The version 2 replaced the version 1
+        assert_eq!(
+            entries[3].event,
+            Event::Merged(MergeKind::Downgrade("2".to_string()))
+        );
+        assert_eq!(entries[4].event, Event::Merged(MergeKind::Rebuild));
+        assert_eq!(
+            rebuilds(&entries),
+            vec![("a/b-1".to_string(), 2, 500), ("a/b-2".to_string(), 1, 200)]
+        );
+        assert_eq!(entries[2].time, 200);
+        assert!(entries[1]
+            .line()
+            .ends_with("  a/b-2  upgraded from 1 (10s)"));
+        assert!(entries[0].is_about("a/b"));
+        assert!(entries[0].is_about("b"));
+        assert!(!entries[0].is_about("a"));
+    }
+
+    #[test]
+    fn history_target_root() {
+        let merge = |version: &str, time, target_root: &str| Merge {
+            time,
+            cpn: "a/b".to_string(),
+            version: version.to_string(),
+            is_binary: false,
+            duration: 10,
+            num: "1 of 1".to_string(),
+            session: 1,
+            target_root: target_root.to_string(),
+            repository: "gentoo".to_string(),
+            slot: String::new(),
+        };
+        let merges = [
+            merge("2", 0, "/"),
+            merge("1", 100, "/mnt/gentoo"),
+            merge("1", 300, "/"),
+        ];
+        let unmerges = [Unmerge {
+            time: 200,
+            cpn: "a/b".to_string(),
+            version: "1".to_string(),
+            duration: 1,
+            autoclean: false,
+            session: 2,
+        }];
+        let events: Vec<Event> = history(&merges, &unmerges)
+            .into_iter()
+            .map(|e| e.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Merged(MergeKind::New),
+                Event::Merged(MergeKind::New),
+                Event::Removed,
+                Event::Merged(MergeKind::Downgrade("2".to_string())),
+            ]
+        );
+
+        let installed = Installed::new(&merges, &unmerges);
+        assert_eq!(
+            installed.classify("a/b", "/", "1"),
+            Some(MergeKind::Rebuild)
+        );
+        assert_eq!(
+            installed.classify("a/b", "/mnt/gentoo", "1"),
+            Some(MergeKind::New)
+        );
+        assert_eq!(installed.classify("a/c", "/", "1"), None);
+    }
+}
@@ -0,0 +1,274 @@
+#![warn(missing_docs)]
+
+//! Run a command and send a notification when a package or a mergelist ends, for `gls watch`
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{io, process::Command};
+
+use crate::{
+    package::Atom,
+    root::Root,
+    session::{Session, SessionStatus},
+    watch::Ended,
+};
+
+/// What ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookKind {
+    /// The emerge of one package
+    Package,
+    /// A session of emerge, with all the packages of its mergelist
+    Mergelist,
+}
+
+/// Something that ended in a root, given to the hooks
+#[derive(Debug, PartialEq)]
+pub struct Transition {
+    /// What ended
+    pub kind: HookKind,
+    /// The full name of the package, or the arguments given to emerge for a mergelist
+    pub name: String,
+    /// How long it took, in seconds
+    pub duration: u32,
+    /// True if it completed, false if it failed or was stopped
+    pub completed: bool,
+    /// For a mergelist, the number of packages merged out of the ones started (`15/16`)
+    pub merged: Option<String>,
+    /// The path of the root
+    pub root: String,
+}
+
+impl Transition {
+    /// Return the transition of a package whose emerge ended in `root`
+    ///
+    /// * `now`: The current time, used for the duration of a failed emerge (the log does not say when it stopped)
+    pub fn package(ended: &Ended, root: &Root, now: u32) -> Self {
+        Self {
+            kind: HookKind::Package,
+            name: ended.package.full_name.clone(),
+            duration: ended
+                .merge
+                .as_ref()
+                .map_or(now.saturating_sub(ended.package.time), |m| m.duration),
+            completed: ended.merge.is_some(),
+            merged: None,
+            root: root.path.clone(),
+        }
+    }
+
+    /// Return the transition of a session that ended in `root` (see [`Session::has_ended`])
+    ///
+    /// The mergelist completed if emerge exited successfully, or was terminated without any failed package.
+    pub fn mergelist(session: &Session, root: &Root, now: u32) -> Self {
+        Self {
+            kind: HookKind::Mergelist,
+            name: session.args.clone(),
+            duration: session.wall_time(now),
+            completed: match session.status {
+                SessionStatus::Success => true,
+                SessionStatus::Terminated => session.failed.is_empty(),
+                _ => false,
+            },
+            merged: Some(format!(
+                "{}/{}",
+                session.merged.len(),
+                session.attempted.len()
+            )),
+            root: root.path.clone(),
+        }
+    }
+
+    /// Return the status given to the hooks: `completed` or `failed`
+    pub fn status(&self) -> &'static str {
+        if self.completed {
+            "completed"
+        } else {
+            "failed"
+        }
+    }
+
+    /// Return the environment variables given to the command of `--hook`
+    ///
+    /// `GLS_EVENT` (`package` or `mergelist`), `GLS_PACKAGE` (see [`Transition::name`]), `GLS_DURATION` (in seconds),
+    /// `GLS_STATUS` (see [`Transition::status`]), `GLS_ROOT`, and `GLS_MERGED` for a mergelist.
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        let event = match self.kind {
+            HookKind::Package => "package",
+            HookKind::Mergelist => "mergelist",
+        };
+        let mut env = vec![
+            ("GLS_EVENT", event.to_string()),
+            ("GLS_PACKAGE", self.name.clone()),
+            ("GLS_DURATION", self.duration.to_string()),
+            ("GLS_STATUS", self.status().to_string()),
+            ("GLS_ROOT", self.root.clone()),
+        ];
+        if let Some(merged) = &self.merged {
+            env.push(("GLS_MERGED", merged.clone()));
+        }
+        env
+    }
+
+    /// Return the transition in one sentence, for the notification
+    ///
+    /// # Examples
+    /// `app-misc/foo-1.0 completed in 2m` or `emerge --update @world failed in 1h 2m, 15/16 merged`
+    pub fn describe(&self) -> String {
+        let mut duration = String::new();
+        Atom::convert_text(self.duration as f64, &mut duration);
+        let name = match self.kind {
+            HookKind::Package => self.name.clone(),
+            HookKind::Mergelist => format!("emerge {}", self.name).trim_end().to_string(),
+        };
+        let mut text = format!("{name} {} in {}", self.status(), duration.trim_end());
+        if let Some(merged) = &self.merged {
+            text += &format!(", {merged} merged");
+        }
+        text
+    }
+
+    /// Run `command` with `sh -c`, with the variables of [`Transition::environment`]
+    ///
+    /// The watch waits for the command to end, so it should not take long.
+    pub fn run(&self, command: &str) -> io::Result<()> {
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .envs(self.environment())
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("the hook {status}")))
+        }
+    }
+
+    /// Send a notification to the desktop over the session D-Bus
+    #[cfg(feature = "notify")]
+    pub fn notify(&self) -> io::Result<()> {
+        let mut notification = notify_rust::Notification::new();
+        notification
+            .appname("gls")
+            .summary("gls")
+            .body(&self.describe());
+        if !self.completed {
+            notification.urgency(notify_rust::Urgency::Critical);
+        }
+        notification.show().map(|_| ()).map_err(io::Error::other)
+    }
+
+    /// Send a notification to the desktop over the session D-Bus
+    ///
+    /// This needs gls to be built with the feature `notify`.
+    #[cfg(not(feature = "notify"))]
+    pub fn notify(&self) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "gls was built without the feature notify",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{package::Merge, parse_file::get_info, root::Paths};
+
+    fn root() -> Root {
+        Root::from_fakeroot("/", &[], &Paths::default())
+    }
+
+    fn ended(completed: bool) -> Ended {
+        let package = get_info("1234567890:  >>> emerge (1 of 2) app/first-1.0 to /").unwrap();
+        Ended {
+            merge: completed.then(|| Merge {
+                time: package.time,
+                cpn: "app/first".to_string(),
+                version: "1.0".to_string(),
+                is_binary: false,
+                duration: 123,
+                num: "1 of 2".to_string(),
+                session: 1,
+                target_root: "/".to_string(),
+                repository: String::new(),
+                slot: String::new(),
+            }),
+            package,
+            peak_rss: None,
+        }
+    }
+
+    #[test]
+    fn package_transition() {
+        let completed = Transition::package(&ended(true), &root(), 1234567990);
+        assert_eq!(completed.duration, 123);
+        assert_eq!(completed.describe(), "app/first-1.0 completed in 2m");
+        assert_eq!(
+            completed.environment(),
+            vec![
+                ("GLS_EVENT", "package".to_string()),
+                ("GLS_PACKAGE", "app/first-1.0".to_string()),
+                ("GLS_DURATION", "123".to_string()),
+                ("GLS_STATUS", "completed".to_string()),
+                ("GLS_ROOT", "/".to_string()),
+            ]
+        );
+
+        // The duration of a failed emerge is counted until now
+        let failed = Transition::package(&ended(false), &root(), 1234567990);
+        assert_eq!(failed.duration, 100);
+        assert_eq!(failed.status(), "failed");
+    }
+
+    #[test]
+    fn mergelist_transition() {
+        let mut session = Session::new(1, 1234567890);
+        session.args = "--update @world".to_string();
+        session.attempted = vec!["app/first-1.0".to_string(), "app/second-2.0".to_string()];
+        session.merged = vec!["app/first-1.0".to_string()];
+        session.failed = vec!["app/second-2.0".to_string()];
+        session.end = Some(1234571490);
+        session.status = SessionStatus::Terminated;
+
+        let failed = Transition::mergelist(&session, &root(), 1234599999);
+        assert_eq!(
+            failed.describe(),
+            "emerge --update @world failed in 1h, 1/2 merged"
+        );
+        assert!(failed
+            .environment()
+            .contains(&("GLS_MERGED", "1/2".to_string())));
+
+        session.failed.clear();
+        assert!(Transition::mergelist(&session, &root(), 0).completed);
+        session.status = SessionStatus::Failure("1".to_string());
+        assert!(!Transition::mergelist(&session, &root(), 0).completed);
+    }
+
+    #[test]
+    fn run_hook() {
+        let transition = Transition::package(&ended(true), &root(), 0);
+        assert!(transition
+            .run(r#"test "$GLS_PACKAGE $GLS_STATUS" = "app/first-1.0 completed""#)
+            .is_ok());
+        assert!(transition.run("exit 3").is_err());
+    }
+}
@@ -24,14 +24,21 @@
 use serde_json::Value;
 use std::fs;
 
-use crate::useful::{get_path_mtimedb, get_size_cpn};
+use crate::{
+    atom::cpn_len,
+    package::{normalize_root, PackageInfo},
+};
 
 /// The kind of information we have in mtimedb, in the "resume" part
 pub struct EmergeResume {
     /// If the type of package is binary
     pub binary: bool,
-    // / What the root to merge the package is
-    // root: String,
+    /// The root the package is merged to
+    pub root: String,
+    /// The repository of the ebuild (mtimedb does not give it, so it is empty unless known from the log)
+    pub repository: String,
+    /// The slot of the package (mtimedb does not give it, so it is empty unless known from the log or the VDB)
+    pub slot: String,
     /// Category of the package
     pub category: String,
     /// The name of the package (without the version)
@@ -46,11 +53,7 @@ impl EmergeResume {
     /// Create a new EmergeResume from a Json value
     fn new(value: &Value) -> Self {
         let binary: bool = value[0] == "binary";
-        // let root = String::from(
-        //     value[1]
-        //         .as_str()
-        //         .expect("The second element should be the root"),
-        // );
+        let root = normalize_root(value[1].as_str().unwrap_or("/"));
         let full_name = String::from(
             value[2]
                 .as_str()
@@ -58,8 +61,7 @@ impl EmergeResume {
         );
         let sla = full_name.find('/').unwrap_or(full_name.len());
         let category = full_name[0..sla].to_string();
-        let name =
-            full_name[sla + 1..get_size_cpn(&full_name).unwrap_or(full_name.len())].to_string();
+        let name = full_name[sla + 1..cpn_len(&full_name)].to_string();
         // let action = String::from(
         //     value[3]
         //         .as_str()
@@ -68,7 +70,9 @@ impl EmergeResume {
 
         EmergeResume {
             binary,
-            // root,
+            root,
+            repository: String::new(),
+            slot: String::new(),
             category,
             name,
             full_name,
@@ -76,28 +80,44 @@ impl EmergeResume {
         }
     }
 
-    pub fn create(binary: bool, full_name: &str) -> Self {
+    /// Create an EmergeResume from what the log tells about a package (the category and the name are left empty)
+    pub fn create(binary: bool, full_name: &str, repository: &str, slot: &str, root: &str) -> Self {
         Self {
             binary,
+            root: root.to_string(),
+            repository: repository.to_string(),
+            slot: slot.to_string(),
             category: "".to_string(),
             name: "".to_string(),
             full_name: full_name.to_string(),
         }
     }
+
+    /// Create an EmergeResume for a package read from the log
+    pub fn from_package(package: &PackageInfo) -> Self {
+        Self {
+            category: package.category.clone(),
+            name: package.name.clone(),
+            ..Self::create(
+                package.is_binary,
+                &package.full_name,
+                &package.repository,
+                &package.slot,
+                &package.target_root,
+            )
+        }
+    }
 }
 
 /// Read mtimedb, extract the list of package that will be used next, and return it
 ///
-/// * `root`: Where to start the path for mtimedb.  
-///   By default the path is /var/cache/db/mtimedb
-pub fn read_mtimedb(root: &str) -> Vec<EmergeResume> {
-    let path = get_path_mtimedb(root);
-
+/// * `path`: The path to mtimedb (see [`Root::mtimedb`](crate::root::Root::mtimedb))
+pub fn read_mtimedb(path: &str) -> Vec<EmergeResume> {
     // Read file
     let content = match fs::read_to_string(path) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Could not read the file from root {root}: {e}");
+            eprintln!("Could not read the file {path}: {e}");
             return vec![];
         }
     };
@@ -123,12 +143,12 @@ pub fn read_mtimedb(root: &str) -> Vec<EmergeResume> {
         None => return vec![],
     };
 
-    return merge
+    merge
         .as_array()
         .unwrap_or(&vec![Value::String("".to_string())])
         .iter()
         .map(|val: &Value| EmergeResume::new(val))
-        .collect();
+        .collect()
 }
 
 #[cfg(test)]
@@ -23,19 +23,168 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 // // // // // // // // // // // // // // // // // // // // // // // //
 
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, time::UNIX_EPOCH};
 
-pub use crate::package::{Atom, PackageInfo};
-pub use crate::parse_file::read_file;
-pub use crate::useful::{correct_path, Arguments, Over};
+pub use crate::atom::{Cpv, Version};
+pub use crate::backtest::{backtest, Accuracy, Backtest, Estimator};
+pub use crate::calibrate::{calibrate, Calibration};
+pub use crate::clock::{Clock, FixedClock, SystemClock};
+pub use crate::export::{read_export, ExportFormat};
+pub use crate::history::{history, rebuilds, Event, HistoryEntry, Installed};
+pub use crate::hooks::{HookKind, Transition};
+pub use crate::liveness::{Liveness, PortageProcess};
+pub use crate::metrics::{read_peaks, save_peak};
+pub use crate::package::{Atom, Merge, MergeKind, PackageInfo, Unmerge};
+pub use crate::parse_file::{read_file, LogState};
+pub use crate::reference::{Reference, ReferenceSource};
+pub use crate::replay::{replay_root, Replay};
+pub use crate::resources::{format_memory, total_memory, Resources, Snapshot, SAMPLE_INTERVAL};
+pub use crate::root::{Paths, Root};
+pub use crate::session::{format_date, parse_date, Session, SessionStatus};
+pub use crate::sync::RepoSync;
+pub use crate::useful::{add_time, correct_path, current_time, Arguments, Command, Over};
+pub use crate::watch::{Changes, Ended, Watcher};
 
 use crate::json::read_mtimedb;
+pub use crate::json::EmergeResume;
 
+mod atom;
+mod backtest;
 mod benchmark;
+mod calibrate;
+mod clock;
+mod export;
+mod history;
+mod hooks;
 mod json;
+mod liveness;
+mod logfile;
+mod metrics;
 mod package;
 mod parse_file;
+mod reference;
+mod replay;
+mod resources;
+mod root;
+mod session;
+mod slot;
+mod sync;
 mod useful;
+mod watch;
+
+/// Read all the logs of `root`, and update `state` as we go.
+///
+/// The histories of all the logs are merged. Files that can not be read are reported (unless `skip_file`) and ignored.
+pub fn read_root(root: &Root, skip_file: bool, state: &mut LogState) {
+    for path in &root.logs {
+        read_log(path, skip_file, state);
+    }
+    state.read_vdb_slots(&root.paths.vdb);
+}
+
+/// Read what is running in `root`, with only the history of the packages concerned
+///
+/// The end of the last log is read first (see [`parse_file::read_tail`]). If something is running, the logs are then read
+/// only for these packages (and the ones in mtimedb with `--full` or `--all`), and for the synchronisations.
+/// If the last log is compressed or was never terminated, all the logs are read (see [`read_root`]).
+pub fn read_root_running(root: &Root, config: &Arguments) -> LogState {
+    let mut state = LogState::default();
+    let Some((last, previous)) = root.logs.split_last() else {
+        return state;
+    };
+    let (start, tail) = match parse_file::read_tail(last) {
+        Ok(Some(found)) => found,
+        _ => {
+            read_root(root, config.skip_file, &mut state);
+            return state;
+        }
+    };
+    if tail.emerges_not_complete.is_empty()
+        && tail.syncs_not_complete.is_empty()
+        && tail.unmerges_not_complete.is_empty()
+    {
+        return tail;
+    }
+
+    let mut needles: Vec<String> = tail
+        .emerges_not_complete
+        .values()
+        .map(PackageInfo::cpn)
+        .collect();
+    if !needles.is_empty() && (config.format.full || config.format.all) {
+        for r in read_mtimedb(&root.paths.mtimedb) {
+            needles.push(r.full_name[..atom::cpn_len(&r.full_name)].to_string());
+        }
+    }
+    let syncs = !tail.syncs_not_complete.is_empty();
+
+    for path in previous {
+        let result = parse_file::read_file_about(path, None, &needles, syncs, &mut state);
+        report_error(result, path, config.skip_file);
+    }
+    let result = parse_file::read_file_about(last, Some(start), &needles, syncs, &mut state);
+    report_error(result, last, config.skip_file);
+    state.append(tail);
+    state.read_vdb_slots(&root.paths.vdb);
+    state
+}
+
+/// Read all the roots at the same time with `read`, and return what we learned from each of them, in the same order
+fn read_in_parallel(roots: &[Root], read: impl Fn(&Root) -> LogState + Sync) -> Vec<LogState> {
+    let read = &read;
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = roots
+            .iter()
+            .map(|root| scope.spawn(move || read(root)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|w| w.join().expect("A thread reading a root panicked"))
+            .collect()
+    })
+}
+
+/// Read all the roots at the same time, and return what we learned from each of them, in the same order
+///
+/// See [`read_root`].
+pub fn read_roots(roots: &[Root], skip_file: bool) -> Vec<LogState> {
+    read_in_parallel(roots, |root| {
+        let mut state = LogState::default();
+        read_root(root, skip_file, &mut state);
+        state
+    })
+}
+
+/// Read what is running in all the roots at the same time, in the same order
+///
+/// See [`read_root_running`].
+pub fn read_roots_running(roots: &[Root], config: &Arguments) -> Vec<LogState> {
+    read_in_parallel(roots, |root| read_root_running(root, config))
+}
+
+/// Report the error of `result` about `what` (a file, a reference...), unless `skip_file`
+///
+/// Return the value of `result` if it is not an error.
+pub fn report_error<T, E: std::fmt::Display>(
+    result: Result<T, E>,
+    what: &str,
+    skip_file: bool,
+) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            if !skip_file {
+                eprintln!("Application error: {e} for {what}");
+            }
+            None
+        }
+    }
+}
+
+/// Read the log at `path` and update `state`. If the file can not be read, report it (unless `skip_file`)
+pub fn read_log(path: &str, skip_file: bool, state: &mut LogState) {
+    report_error(read_file(path, state), path, skip_file);
+}
 
 /// Return the time of an emerge as a string, with some more information
 ///
@@ -57,7 +206,15 @@ fn get_time_emerge(t: f64, over: Over) -> String {
 }
 
 fn set_package_time(package: &PackageInfo, completed_atoms: &mut HashMap<String, Atom>) {
-    if let Some(atom) = completed_atoms.get_mut(&package.cpn()) {
+    let cpn = package.cpn();
+    let key = find_atom(
+        completed_atoms,
+        &cpn,
+        &package.repository,
+        &package.target_root,
+    )
+    .map(|atom| atom.key());
+    if let Some(atom) = key.and_then(|k| completed_atoms.get_mut(&k)) {
         atom.last_time = package.time
     }
 }
@@ -73,43 +230,22 @@ pub fn set_last_time(
     }
 }
 
-/// Test the existence of the file `log_emerge`, and if found, return the last line
-///
-/// * `log_emerge`: The basis of the path for the log
-/// * `time`: When the emerge could have been started
-fn test_file(log_emerge: &str, time: u32) -> String {
-    let mut path = log_emerge.to_string();
-    let datetime = chrono::DateTime::from_timestamp(time.into(), 0).unwrap();
-    let date = datetime.format("%Y%m%d-%H%M%S");
-
-    path.push_str(&format!(":{date}.log"));
-
-    return match std::fs::exists(&path) {
-        Ok(true) => match fs::read_to_string(path) {
-            Ok(content) => content.lines().last().unwrap_or("").to_string(),
-            Err(_) => "".to_string(),
-        },
-        _ => "".to_string(),
-    };
+/// Return for how long the build log of `p` was not written at `now`, if it exists (see [`logfile::build_log_path`])
+fn build_log_silence(p: &PackageInfo, root: &Root, now: u32) -> Option<u32> {
+    let path = logfile::build_log_path(&root.paths.build_log, &p.full_name, p.time)?;
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    let modified = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(u64::from(now).saturating_sub(modified) as u32)
 }
 
-/// Read the advancement from the file in log/portage/build (that is why you need split-log in your FEATURE variable)
+/// Read the advancement from the build log of `p` (see [`logfile::build_log_path`])
 ///
-/// This function only read the last line (it uses [`test_file`]), so if the compiler show wome warnings, the progression will not appear.
-fn ninja_read(p: &PackageInfo, root: &str, output: &mut String) {
-    let mut log_emerge = String::new();
-    correct_path(root, "/var/log/portage/build/", &mut log_emerge);
-    log_emerge.push_str(&p.full_name);
+/// This function only read the last line, so if the compiler show wome warnings, the progression will not appear.
+fn ninja_read(p: &PackageInfo, root: &Root, output: &mut String) {
     output.push(' ');
-
-    // Test 3 files, as there may be slight delay between when the line was written in emerge.log, and when the file was created
-    let mut line = test_file(&log_emerge, p.time + 1);
-    if line.is_empty() {
-        line = test_file(&log_emerge, p.time);
-        if line.is_empty() {
-            line = test_file(&log_emerge, p.time - 1);
-        }
-    }
+    let line = logfile::build_log_path(&root.paths.build_log, &p.full_name, p.time)
+        .and_then(|path| logfile::last_line(&path))
+        .unwrap_or_default();
 
     // Ninja show progress using '[x/y] cmd'
     if !line.is_empty() && line.starts_with("[") {
@@ -123,13 +259,59 @@ fn ninja_read(p: &PackageInfo, root: &str, output: &mut String) {
     }
 }
 
+/// Return the history of `cpn` emerged to `target_root` from `repository`
+///
+/// If the repository is unknown (mtimedb does not give it, and the log gives it only after the start of the emerge),
+/// use the history with the most emerges among the repositories.
+fn find_atom<'a>(
+    completed_atoms: &'a HashMap<String, Atom>,
+    cpn: &str,
+    repository: &str,
+    target_root: &str,
+) -> Option<&'a Atom> {
+    let key = package::history_key(cpn, repository, target_root);
+    if !repository.is_empty() || completed_atoms.contains_key(&key) {
+        return completed_atoms.get(&key);
+    }
+    completed_atoms
+        .values()
+        .filter(|a| (a.cpn == cpn) && (a.target_root == target_root))
+        .max_by_key(|a| a.num_emerge)
+}
+
 /// Return the time taken by the package
 ///
+/// If the package is not in `completed_atoms`, fall back to the histories of the other machines (see [`reference::lookup`]).  
 /// Returns (-1, _) if the time is unknow (because never emerged before)
-fn get_time_package(cpn: &str, completed_atoms: &HashMap<String, Atom>) -> (f64, Over) {
+///
+/// * `r`: The package, with its repository and target root
+/// * `cpn`: The category/name of the package
+/// * `start`: When the emerge started
+/// * `clock`: Gives the current time (see [`Atom::comp_avg`])
+fn get_time_package(
+    r: &json::EmergeResume,
+    cpn: &str,
+    start: u32,
+    completed_atoms: &HashMap<String, Atom>,
+    references: &[Reference],
+    clock: &dyn Clock,
+) -> (f64, Over) {
     let mut over = Over::NO;
-    let time = match completed_atoms.get(cpn) {
-        Some(atom) => atom.comp_avg(&mut over),
+    let key = package::history_key(cpn, &r.repository, &r.root);
+    let atom = find_atom(completed_atoms, cpn, &r.repository, &r.root);
+    // Only use the times of the slot of the package, as the slots installed in parallel can differ a lot
+    let version = atom::split_cpv(&r.full_name).map_or("", |(_, v)| v);
+    let in_slot = atom.and_then(|a| {
+        let slot = if r.slot.is_empty() {
+            a.slot_of(version)?
+        } else {
+            &r.slot
+        };
+        a.for_slot(slot)
+    });
+    let local = in_slot.as_ref().or(atom);
+    let time = match reference::lookup(&key, local, references, start) {
+        Some(atom) => atom.comp_avg(clock, &mut over),
         None => -1.,
     };
     (time, over)
@@ -138,34 +320,43 @@ fn get_time_package(cpn: &str, completed_atoms: &HashMap<String, Atom>) -> (f64,
 /// Return the time the package would need to be installed
 ///
 /// If we know the package is binary, then we get a shortcut
-fn get_time(r: &json::EmergeResume, completed_atoms: &HashMap<String, Atom>) -> (f64, Over) {
+fn get_time(
+    r: &json::EmergeResume,
+    start: u32,
+    completed_atoms: &HashMap<String, Atom>,
+    references: &[Reference],
+    clock: &dyn Clock,
+) -> (f64, Over) {
     // If package in waiting list is binary, add 2 minutes
     if r.binary {
         return (120.0, Over::NO);
     }
     // Otherwise, get the cpn from the name ...
-    let size = useful::get_size_cpn(&r.full_name).unwrap_or(r.full_name.len());
-    let cpn = &r.full_name.as_str()[..size];
+    let cpn = &r.full_name[..atom::cpn_len(&r.full_name)];
     // ... and compute the time
-    get_time_package(cpn, completed_atoms)
+    get_time_package(r, cpn, start, completed_atoms, references, clock)
 }
 
 /// Read all the packages from mtimedb and add all their times.
 ///
 /// If the time of one package in unknow, then the time for the sum is also unknow
 ///
-/// * `fakeroot`: The folder from which we will try to access mtimedb
+/// * `resume`: The packages read from mtimedb
 /// * `completed_atoms`: The HashMap of completed atoms
+/// * `references`: The histories of the other machines
+/// * `clock`: Gives the current time
 /// * `output`: Where the time will be placed after formatting
 fn compile_resumelist(
-    fakeroot: &str,
+    resume: &[EmergeResume],
     completed_atoms: &HashMap<String, Atom>,
+    references: &[Reference],
+    clock: &dyn Clock,
     output: &mut String,
 ) {
-    let resume = read_mtimedb(fakeroot);
+    let now = clock.now();
     let mut time = 0.0;
     for r in resume {
-        let (t, _) = get_time(&r, completed_atoms);
+        let (t, _) = get_time(r, now, completed_atoms, references, clock);
         time += t;
         if t < 0.0 {
             // If the time is < 0, then we never encountered it and don't know
@@ -195,17 +386,28 @@ fn format_time(time: f64, over: Over, output: &mut String) {
 ///
 /// * `emerge`: The package we want to know more about
 /// * `completed_atoms`: The HashMap storing the completed atoms
+/// * `installed`: The versions installed according to the log, binary merges included (see [`Installed`])
+/// * `references`: The histories of the other machines
 /// * `config`: The configuration of the running program
-/// * `fakeroot`: Will be passed to `compile_resumelist`, only used when `--full`
+/// * `root`: Will be passed to `ninja_read`
+/// * `resume`: The packages from mtimedb, passed to `compile_resumelist` (only used when `--full`)
+/// * `clock`: Gives the current time
+/// * `resources`: What the build uses, shown with `--resources` (see [`Liveness::measure`])
+#[allow(clippy::too_many_arguments)]
 fn status_package(
     emerge: &PackageInfo,
     completed_atoms: &HashMap<String, Atom>,
+    installed: &Installed,
+    references: &[Reference],
     config: &Arguments,
-    fakeroot: &str,
+    root: &Root,
+    resume: &[EmergeResume],
+    clock: &dyn Clock,
+    resources: Option<&Resources>,
 ) -> Option<(String, f64)> {
-    let time = useful::current_time() as u32;
-    // If the emerge started a week ago, skip it
-    if time - emerge.time > 7 * 24 * 60 * 60 {
+    let time = clock.now();
+    // If the emerge started too long ago, it was killed without writing it in the log
+    if config.is_too_old(emerge.time, time) {
         return None;
     }
 
@@ -214,18 +416,64 @@ fn status_package(
         output.push_str(&format!("{}, ", emerge.num));
     }
     output.push_str(&emerge.full_name);
+    if emerge.target_root != "/" {
+        output.push_str(&format!(" to {}", emerge.target_root));
+    }
+    let previous = find_atom(
+        completed_atoms,
+        &emerge.cpn(),
+        &emerge.repository,
+        &emerge.target_root,
+    );
+    let kind = installed
+        .classify(&emerge.cpn(), &emerge.target_root, &emerge.version())
+        .or_else(|| match previous {
+            None => Some(MergeKind::New),
+            // An atom without versions was not read from a log, so we know nothing of its previous merge
+            Some(atom) => atom
+                .last_version()
+                .map(|last| MergeKind::classify(Some(last), &emerge.version())),
+        });
+    if let Some(kind) = kind {
+        output.push_str(&format!(" ({})", kind.describe()));
+    }
+
+    // A build that does not write anything for so long is not running anymore, so its time is unknow
+    if let Some(silence) = build_log_silence(emerge, root, time)
+        .filter(|silence| (config.stall_after > 0) && (*silence > config.stall_after))
+    {
+        output.push_str(&format!(
+            " is stalled, its build log was not written for {}",
+            useful::format_duration(silence)
+        ));
+        return Some((output, -1.0));
+    }
+
     let (t, over) = get_time(
-        &json::EmergeResume::create(emerge.is_binary, &emerge.cpn()),
+        &json::EmergeResume::create(
+            emerge.is_binary,
+            &emerge.full_name,
+            &emerge.repository,
+            &emerge.slot,
+            &emerge.target_root,
+        ),
+        emerge.time,
         completed_atoms,
+        references,
+        clock,
     );
     format_time(t, over, &mut output);
 
     if config.read_ninja {
-        ninja_read(emerge, fakeroot, &mut output);
+        ninja_read(emerge, root, &mut output);
+    }
+
+    if let Some(r) = resources {
+        output.push_str(&format!(" ({})", r.describe()));
     }
 
     if config.format.full {
-        compile_resumelist(fakeroot, completed_atoms, &mut output);
+        compile_resumelist(resume, completed_atoms, references, clock, &mut output);
     }
 
     Some((output, t))
@@ -235,84 +483,245 @@ fn status_package(
 ///
 /// * `p`: The package we want more information on
 /// * `completed_atoms`: The HashMap storing the completed atoms
+/// * `merges`: The merges of the log (see [`status_package`])
+/// * `references`: The histories of the other machines
 /// * `config`: The configuration of the running program
-/// * `fakeroot`: Where to search for mtimedb, and to change to name shown
+/// * `root`: Where to search for the build logs, and the name shown
+/// * `resume`: The packages from mtimedb
+/// * `clock`: Gives the current time
+/// * `resources`: What the build uses (see [`status_package`])
 /// * `print`: Where the formatted output will be put
 ///
 /// # Examples
 /// The kind of output will be like  
 /// `1 of 2, sys-devel/gcc-13.3.1_p20240614, ETA: 3h 1m` for a classical output  
 /// `gentoo: 51 of 51, media-gfx/krita-5.2.6 is over by a few seconds [225/3346]` for an output with --show-root --fakeroot /mnt/gentoo --read-ninja
+#[allow(clippy::too_many_arguments)]
 fn emerge_package(
     p: &PackageInfo,
     completed_atoms: &HashMap<String, Atom>,
+    installed: &Installed,
+    references: &[Reference],
     config: &Arguments,
-    fakeroot: &str,
+    root: &Root,
+    resume: &[EmergeResume],
+    clock: &dyn Clock,
+    resources: Option<&Resources>,
     print: &mut String,
 ) -> f64 {
-    let mut out = String::new();
-    if config.show_root && (fakeroot != "/") {
-        let name = std::path::Path::new(fakeroot).components().next_back();
-        if let Some(val) = name {
-            out.push_str(val.as_os_str().to_str().unwrap_or(""));
-            out.push_str(": ");
-        }
-    }
-    let (status, time) =
-        status_package(p, completed_atoms, config, fakeroot).unwrap_or(("".to_string(), -1.0));
+    let mut out = root_prefix(config, root);
+    let (status, time) = status_package(
+        p,
+        completed_atoms,
+        installed,
+        references,
+        config,
+        root,
+        resume,
+        clock,
+        resources,
+    )
+    .unwrap_or(("".to_string(), -1.0));
     out.push_str(&status);
 
     print.push_str(&format!("{out}\n"));
     time
 }
 
-/// The function you should use the get the emerge time for all packages in `emerges_not_complete` and in mtimedb if config allows you.
-pub fn get_emerges(
-    emerges_not_complete: &HashMap<String, PackageInfo>,
-    completed_atoms: &mut HashMap<String, Atom>,
+/// Return the text to put before each line of `root`: its name if `--show-root` is used
+fn root_prefix(config: &Arguments, root: &Root) -> String {
+    if config.show_root && !root.name.is_empty() {
+        format!("{}: ", root.name)
+    } else {
+        String::new()
+    }
+}
+
+/// Return the line showing `total` preceded by `label`, or 'Unknow' if `total` is less than zero
+pub fn format_total(label: &str, total: f64) -> String {
+    let mut out = format!("{label}: ");
+    if total < 0.0 {
+        out.push_str("Unknow");
+    } else {
+        Atom::convert_text(total, &mut out);
+        out.pop();
+    }
+    out.push('\n');
+    out
+}
+
+/// Put in `print` the status of the synchronisations running in `state`, and return the time until they are all done
+///
+/// The time of a synchronisation is the average of the previous ones of the same repository.
+/// Return less than zero if the time of one of them is unknow.
+///
+/// # Examples
+/// `Syncing gentoo, ETA: 40s`
+pub fn get_syncs(
+    state: &LogState,
     config: &Arguments,
-    fakeroot: &str,
+    root: &Root,
+    clock: &dyn Clock,
+    print: &mut String,
+) -> f64 {
+    let now = clock.now();
+    let mut running: Vec<(&String, &u32)> = state.syncs_not_complete.iter().collect();
+    running.sort();
+
+    let mut total = 0.0;
+    for (repo, start) in running {
+        let elapsed = now.saturating_sub(*start);
+        // If the synchronisation started too long ago, it was killed without writing it in the log
+        if config.is_too_old(*start, now) {
+            continue;
+        }
+
+        let mut out = format!("{}Syncing {repo}", root_prefix(config, root));
+        let t = match sync::average_duration(repo, &state.syncs) {
+            Some(avg) if avg >= elapsed => {
+                out.push_str(&format!(
+                    ", ETA: {}",
+                    useful::format_duration(avg - elapsed)
+                ));
+                (avg - elapsed) as f64
+            }
+            Some(avg) => {
+                out.push_str(&format!(
+                    " is over by {}",
+                    useful::format_duration(elapsed - avg)
+                ));
+                0.0
+            }
+            None => {
+                out.push_str(", Unknow");
+                -1.0
+            }
+        };
+        print.push_str(&format!("{out}\n"));
+        total = useful::add_time(total, t);
+    }
+
+    total
+}
+
+/// Put in `print` the packages being unmerged in `state`, with the number of packages already unmerged in the session
+///
+/// # Examples
+/// `Unmerging dev-lang/rust-1.80.1, 12 unmerged`
+pub fn get_unmerges(
+    state: &LogState,
+    config: &Arguments,
+    root: &Root,
+    clock: &dyn Clock,
     print: &mut String,
 ) {
+    let now = clock.now();
+    let mut running: Vec<(&String, &Unmerge)> = state.unmerges_not_complete.iter().collect();
+    running.sort_by_key(|(_, u)| u.time);
+
+    for (full_name, unmerge) in running {
+        // If the unmerge started too long ago, it was killed without writing it in the log
+        if config.is_too_old(unmerge.time, now) {
+            continue;
+        }
+        let done = state
+            .unmerges
+            .iter()
+            .filter(|u| u.session == unmerge.session)
+            .count();
+        print.push_str(&format!(
+            "{}Unmerging {full_name}, {done} unmerged\n",
+            root_prefix(config, root)
+        ));
+    }
+}
+
+/// Return the packages that the emerge of `root` will merge, read from its mtimedb, if config needs them (`--full` or `--all`)
+pub fn read_resume(config: &Arguments, root: &Root) -> Vec<EmergeResume> {
+    if config.format.full || config.format.all {
+        read_mtimedb(&root.paths.mtimedb)
+    } else {
+        vec![]
+    }
+}
+
+/// The function you should use the get the emerge time for all packages in [`LogState::emerges_not_complete`] and in `resume` if config allows you.
+///
+/// All the lines are for `root`, whose files are found with its [`Paths`](Root::paths), and `resume` is the list of the packages to merge (see [`read_resume`]).
+/// `references` are used for the packages [`LogState::completed_atoms`] does not know (or always if in blend mode).
+/// `clock` gives the current time, used for the time elapsed since the start of the emerges.
+/// Return the total time needed for the root (less than zero if unknow).
+pub fn get_emerges(
+    state: &mut LogState,
+    references: &[Reference],
+    config: &Arguments,
+    root: &Root,
+    resume: &[EmergeResume],
+    clock: &dyn Clock,
+    print: &mut String,
+) -> f64 {
+    let emerges_not_complete = &state.emerges_not_complete;
+    let completed_atoms = &mut state.completed_atoms;
+    let installed = Installed::new(&state.merges, &state.unmerges);
+    let resources = &state.resources;
+
     let mut total = 0.0;
     for package in emerges_not_complete.values() {
-        let t = emerge_package(package, completed_atoms, config, fakeroot, print);
+        let t = emerge_package(
+            package,
+            completed_atoms,
+            &installed,
+            references,
+            config,
+            root,
+            resume,
+            clock,
+            resources.get(&package.full_name),
+            print,
+        );
         total = useful::add_time(total, t);
     }
 
     if config.format.all {
         // Create next_emerge from data from mtimedb
-        let list = read_mtimedb(fakeroot);
-        for p in list {
+        for p in resume {
             if emerges_not_complete.get(&p.full_name).is_some() {
                 continue;
             }
             let package = PackageInfo {
-                category: p.category,
-                name: p.name,
-                full_name: p.full_name,
-                time: useful::current_time() as u32,
+                category: p.category.clone(),
+                name: p.name.clone(),
+                full_name: p.full_name.clone(),
+                time: clock.now(),
                 is_binary: p.binary,
                 num: "".to_string(),
+                target_root: p.root.clone(),
+                repository: p.repository.clone(),
+                slot: p.slot.clone(),
             };
 
             set_package_time(&package, completed_atoms);
 
-            let t = emerge_package(&package, completed_atoms, config, fakeroot, print);
+            let t = emerge_package(
+                &package,
+                completed_atoms,
+                &installed,
+                references,
+                config,
+                root,
+                resume,
+                clock,
+                None,
+                print,
+            );
             total = useful::add_time(total, t);
         }
 
-        let mut out = String::from("Total: ");
-        if total < 0.0 {
-            out.push_str("Unknow");
-        } else {
-            Atom::convert_text(total, &mut out);
-            out = out[..out.len() - 1].to_string();
-        }
-        out.push('\n');
-
-        print.push_str(&out);
+        let label = format!("{}Total", root_prefix(config, root));
+        print.push_str(&format_total(&label, total));
     }
+
+    total
 }
 
 #[cfg(test)]
@@ -321,17 +730,134 @@ mod tests {
 
     use super::*;
 
+    /// The current time during the tests
+    const CLOCK: FixedClock = FixedClock(1234567890);
+
+    #[test]
+    fn get_syncs_running() {
+        let mut state = read_file_test("./tests/emerge.log/sync");
+        let config = get_default_config();
+        let mut print = String::new();
+
+        // The last sync of gentoo started 4s ago, and they took 35s in average
+        let total = get_syncs(&state, &config, &default_root(), &CLOCK, &mut print);
+        assert_eq!(print, "Syncing gentoo, ETA: 31s\n");
+        assert_eq!(total, 31.0);
+
+        state
+            .syncs_not_complete
+            .insert("guru".to_string(), 1234567880);
+        print.clear();
+        let total = get_syncs(&state, &config, &default_root(), &CLOCK, &mut print);
+        assert_eq!(print, "Syncing gentoo, ETA: 31s\nSyncing guru, Unknow\n");
+        assert!(total < 0.0);
+    }
+
+    #[test]
+    fn get_unmerges_running() {
+        let state = read_file_test("./tests/emerge.log/unmerge");
+        let mut print = String::new();
+        get_unmerges(
+            &state,
+            &get_default_config(),
+            &default_root(),
+            &CLOCK,
+            &mut print,
+        );
+        assert_eq!(print, "Unmerging category/package-1.3, 1 unmerged\n");
+    }
+
+    #[test]
+    fn read_root_rotated() {
+        let mut config = get_default_config();
+        config.files = vec!["./tests/rotated/emerge.log".to_string()];
+        config.fakeroots = vec![".".to_string()];
+        config.include_rotated = true;
+        let root = &config.get_roots()[0];
+        assert_eq!(root.logs.len(), 6);
+
+        let mut state = LogState::default();
+        read_root(root, false, &mut state);
+        // The rotated logs are read from the oldest to the newest
+        let versions: Vec<&str> = state.merges.iter().map(|m| m.version.as_str()).collect();
+        assert_eq!(versions, ["1.1", "1.2", "1.3", "1.4", "1.5", "1.6"]);
+    }
+
+    #[test]
+    fn read_root_running_same() {
+        let mut config = get_default_config();
+        for file in ["sessions", "slots", "sync", "unmerge", "binary_running"] {
+            config.files = vec![format!("./tests/emerge.log/{file}")];
+            config.fakeroots = vec![".".to_string()];
+            let root = &config.get_roots()[0];
+            let mut full = LogState::default();
+            read_root(root, false, &mut full);
+            let running = read_root_running(root, &config);
+
+            let mut print_full = String::new();
+            let mut print_running = String::new();
+            for (state, print) in [(&full, &mut print_full), (&running, &mut print_running)] {
+                get_unmerges(state, &config, root, &CLOCK, print);
+                get_syncs(state, &config, root, &CLOCK, print);
+                for package in state.emerges_not_complete.values() {
+                    emerge_package(
+                        package,
+                        &state.completed_atoms,
+                        &Installed::new(&state.merges, &state.unmerges),
+                        &[],
+                        &config,
+                        root,
+                        &[],
+                        &CLOCK,
+                        None,
+                        print,
+                    );
+                }
+            }
+            assert!(!print_full.is_empty(), "{file}");
+            assert_eq!(print_running, print_full, "{file}");
+        }
+    }
+
     #[test]
-    fn test_file_dont_exist() {
-        let file = "/foo/bar";
-        let time = 0;
-        assert_eq!(test_file(file, time), "");
+    fn build_log_dont_exist() {
+        let emerge = create_default_situation().2;
+        let root = default_root();
+        assert_eq!(
+            logfile::build_log_path(&root.paths.build_log, &emerge.full_name, emerge.time),
+            None
+        );
+    }
+
+    fn build_log_root() -> Root {
+        let paths = Paths {
+            build_log: "./tests/build/".to_string(),
+            ..Paths::default()
+        };
+        Root::from_fakeroot("/", &get_default_config().files, &paths)
+    }
+
+    #[test]
+    fn build_log_ninja() {
+        let emerge = create_default_situation().2;
+        let root = build_log_root();
+        assert_eq!(
+            logfile::build_log_path(&root.paths.build_log, &emerge.full_name, emerge.time)
+                .as_deref(),
+            Some("./tests/build/app/testing-0.0.0:20090213-233131.log")
+        );
+        let mut output = String::new();
+        ninja_read(&emerge, &root, &mut output);
+        assert_eq!(output, " [12/345]");
     }
 
     fn get_default_config() -> Arguments {
         Arguments {
             files: vec!["./emerge.log".to_string()],
             fakeroots: vec!["/".to_string()],
+            command: None,
+            roots: vec![],
+            references: vec![],
             format: useful::Format {
                 full: false,
                 all: false,
@@ -339,9 +865,20 @@ mod tests {
             read_ninja: false,
             show_root: false,
             skip_file: false,
+            include_rotated: false,
+            now: None,
+            no_proc: false,
+            resources: false,
+            max_age: 7 * 24 * 60 * 60,
+            stall_after: 2 * 60 * 60,
+            repository: None,
         }
     }
 
+    fn default_root() -> Root {
+        Root::from_fakeroot("/", &get_default_config().files, &Paths::default())
+    }
+
     fn create_empty_hashmap() -> HashMap<String, Atom> {
         let m: HashMap<String, Atom> = HashMap::new();
         m
@@ -378,7 +915,17 @@ mod tests {
         emerge.time = 0;
         let map = default.1;
         let config = default.0;
-        let status = status_package(&emerge, &map, &config, "/");
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
         assert!(status.is_none());
     }
 
@@ -389,8 +936,293 @@ mod tests {
         let mut map = default.1;
         map.clear();
         let config = default.0;
-        let status = status_package(&emerge, &map, &config, "/");
-        assert_eq!(status.unwrap().0, "1 of 1, app/testing-0.0.0, Unknow");
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(status.unwrap().0, "1 of 1, app/testing-0.0.0 (new), Unknow");
+    }
+
+    #[test]
+    fn status_package_reference_fallback() {
+        let (config, map, emerge) = create_default_situation();
+        let references = [Reference {
+            source: ReferenceSource {
+                name: "box".to_string(),
+                logs: vec![],
+                factor: 6.0,
+                blend: false,
+            },
+            atoms: map,
+        }];
+        let status = status_package(
+            &emerge,
+            &HashMap::new(),
+            &Installed::default(),
+            &references,
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(
+            status.unwrap().0,
+            "1 of 1, app/testing-0.0.0 (new), ETA: 2m"
+        );
+    }
+
+    #[test]
+    fn find_atom_repository() {
+        let state = read_file_test("./tests/emerge.log/repository");
+        let atoms = &state.completed_atoms;
+        let atom = find_atom(atoms, "category/package", "guru", "/").unwrap();
+        assert_eq!(atom.total_time, 20);
+        // Unknow repository: the history with the most emerges
+        assert!(find_atom(atoms, "category/package", "", "/").is_some());
+        assert!(find_atom(atoms, "category/package", "other", "/").is_none());
+        assert!(find_atom(atoms, "category/package", "", "/mnt/").is_none());
+    }
+
+    #[test]
+    fn status_package_target_root() {
+        let (config, map, mut emerge) = create_default_situation();
+        emerge.target_root = "/usr/aarch64-linux-gnu/".to_string();
+        // The native history is not used for another root
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(
+            status.unwrap().0,
+            "1 of 1, app/testing-0.0.0 to /usr/aarch64-linux-gnu/ (new), Unknow"
+        );
+    }
+
+    #[test]
+    fn status_package_kind() {
+        let (config, mut map, mut emerge) = create_default_situation();
+        let atom = map.get_mut("app/testing").unwrap();
+        atom.versions.push(("app/testing-0.0.1".to_string(), 10));
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(
+            status.unwrap().0,
+            "1 of 1, app/testing-0.0.0 (downgrade from 0.0.1), ETA: 1m"
+        );
+        emerge.full_name = "app/testing-0.0.1".to_string();
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(
+            status.unwrap().0,
+            "1 of 1, app/testing-0.0.1 (rebuild), ETA: 1m"
+        );
+    }
+
+    #[test]
+    fn status_package_kind_binary() {
+        let mut state = LogState::default();
+        for line in [
+            "1234560000:  >>> emerge (1 of 1) app/testing-0.0.0 to /",
+            "1234560060:  ::: completed emerge (1 of 1) app/testing-0.0.0 to /",
+            "1234561000:  >>> emerge (1 of 1) app/testing-0.0.1 to /",
+            "1234561000:  === (1 of 1) Merging Binary (app/testing-0.0.1::/var/cache/binpkgs/app/testing-0.0.1.gpkg.tar)",
+            "1234561010:  ::: completed emerge (1 of 1) app/testing-0.0.1 to /",
+        ] {
+            parse_file::act_on_line(line, &mut state);
+        }
+        let (config, _, mut emerge) = create_default_situation();
+        emerge.full_name = "app/testing-0.0.1".to_string();
+        // The binary merge is not in the atoms, and the package moved to another repository
+        emerge.repository = "guru".to_string();
+        let status = status_package(
+            &emerge,
+            &state.completed_atoms,
+            &Installed::new(&state.merges, &state.unmerges),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert!(status
+            .unwrap()
+            .0
+            .starts_with("1 of 1, app/testing-0.0.1 (rebuild)"));
+    }
+
+    #[test]
+    fn status_package_slot() {
+        let mut state = read_file_test("./tests/emerge.log/slots");
+        set_last_time(&state.emerges_not_complete, &mut state.completed_atoms);
+        let config = get_default_config();
+        let emerge = &state.emerges_not_complete["sys-devel/llvm-18.1.8"];
+        // Only the emerges of llvm:18 are used, the one of llvm:15 is much shorter
+        let status = status_package(
+            emerge,
+            &state.completed_atoms,
+            &Installed::new(&state.merges, &state.unmerges),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
+        assert_eq!(
+            status.unwrap().0,
+            "1 of 1, sys-devel/llvm-18.1.8 (upgrade from 18.1.7), ETA: 6m"
+        );
+    }
+
+    #[test]
+    fn status_package_stalled() {
+        let (mut config, map, emerge) = create_default_situation();
+        config.max_age = u32::MAX;
+        let root = build_log_root();
+
+        // The build log was written long before this time
+        let later = FixedClock(u32::MAX);
+        let (status, time) = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &root,
+            &[],
+            &later,
+            None,
+        )
+        .unwrap();
+        assert!(
+            status.starts_with(
+                "1 of 1, app/testing-0.0.0 is stalled, its build log was not written for "
+            ),
+            "{status}"
+        );
+        assert_eq!(time, -1.0);
+
+        config.stall_after = 0;
+        let (status, _) = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &root,
+            &[],
+            &later,
+            None,
+        )
+        .unwrap();
+        assert!(!status.contains("stalled"), "{status}");
+
+        // The build log was written after the start
+        config.stall_after = 2 * 60 * 60;
+        let (status, _) = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &root,
+            &[],
+            &CLOCK,
+            None,
+        )
+        .unwrap();
+        assert!(!status.contains("stalled"), "{status}");
+    }
+
+    #[test]
+    fn status_package_resources() {
+        let (config, map, emerge) = create_default_situation();
+        let resources = Resources {
+            jobs: 3,
+            rss: 2 * 1024 * 1024,
+            cpu: 290.0,
+        };
+        let (status, _) = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            Some(&resources),
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            "1 of 1, app/testing-0.0.0, ETA: 1m (3 jobs, 2.0 GB, 290% CPU)"
+        );
+    }
+
+    #[test]
+    fn status_package_max_age() {
+        let (mut config, map, emerge) = create_default_situation();
+        let day_after = FixedClock(emerge.time + 24 * 60 * 60);
+        assert!(status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &day_after,
+            None
+        )
+        .is_some());
+        config.max_age = 60 * 60;
+        assert!(status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &day_after,
+            None
+        )
+        .is_none());
     }
 
     #[test]
@@ -399,21 +1231,51 @@ mod tests {
         let emerge = default.2;
         let map = default.1;
         let config = default.0;
-        let status = status_package(&emerge, &map, &config, "/");
+        let status = status_package(
+            &emerge,
+            &map,
+            &Installed::default(),
+            &[],
+            &config,
+            &default_root(),
+            &[],
+            &CLOCK,
+            None,
+        );
         assert_eq!(status.unwrap().0, "1 of 1, app/testing-0.0.0, ETA: 1m");
     }
 
     #[test]
     fn emerge_package_binary_running() {
-        let (emerges_not_complete, completed_atoms) =
-            read_file_test("./tests/emerge.log/binary_running");
+        let state = read_file_test("./tests/emerge.log/binary_running");
         let config = get_default_config();
+        let root = default_root();
         let mut print = String::new();
 
-        for package in emerges_not_complete.values() {
-            emerge_package(package, &completed_atoms, &config, "/", &mut print);
+        for package in state.emerges_not_complete.values() {
+            emerge_package(
+                package,
+                &state.completed_atoms,
+                &Installed::new(&state.merges, &state.unmerges),
+                &[],
+                &config,
+                &root,
+                &[],
+                &CLOCK,
+                None,
+                &mut print,
+            );
         }
 
-        assert_eq!(print, "1 of 1, category/package-1.2.3, ETA: 2m\n");
+        assert_eq!(print, "1 of 1, category/package-1.2.3 (new), ETA: 2m\n");
+    }
+
+    #[test]
+    fn format_total_known() {
+        assert_eq!(format_total("Total", 3.0 * 60.0), "Total: 3m\n");
+        assert_eq!(
+            format_total("gentoo: Total", -1.0),
+            "gentoo: Total: Unknow\n"
+        );
     }
 }
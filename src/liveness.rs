@@ -0,0 +1,354 @@
+#![warn(missing_docs)]
+
+//! Check in /proc that the emerges read from the log are still running
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{collections::HashSet, fs, os::unix::fs::MetadataExt, path::Path};
+
+use crate::{
+    parse_file::LogState,
+    resources::{Resources, Snapshot},
+    root::Root,
+    useful::correct_path,
+};
+
+/// A process of portage found in /proc: emerge itself, or a process building a package
+#[derive(Debug, PartialEq)]
+pub struct PortageProcess {
+    /// The id of the process
+    pub pid: u32,
+    /// The folder the process is chrooted in (`None` if we are not allowed to know it)
+    pub root: Option<String>,
+    /// The full name of the package built by the process (`None` for emerge)
+    pub package: Option<String>,
+}
+
+impl PortageProcess {
+    /// Read the process `pid` in `proc`, and return it if it is a process of portage
+    ///
+    /// The package is given by the title of the ebuild processes ('[cat/pf] sandbox ...'),
+    /// or by the variables CATEGORY and PF of their environment (that the compilers inherit).
+    fn read(proc: &str, pid: u32) -> Option<Self> {
+        let dir = format!("{proc}/{pid}");
+        let cmdline = fs::read(format!("{dir}/cmdline")).ok()?;
+        let args: Vec<String> = cmdline
+            .split(|b| *b == 0)
+            .map(|arg| String::from_utf8_lossy(arg).to_string())
+            .collect();
+        // The environment of the processes of root cannot be read by the other users
+        let environ = fs::read(format!("{dir}/environ")).unwrap_or_default();
+
+        let package = package_from_title(&args).or_else(|| package_from_environ(&environ));
+        if package.is_none() && !is_emerge(&args) {
+            return None;
+        }
+
+        let root = fs::read_link(format!("{dir}/root"))
+            .ok()
+            .map(|link| link.to_string_lossy().to_string());
+        Some(Self { pid, root, package })
+    }
+
+    /// Return true if the process runs in `root`, or if we do not know where it runs
+    fn is_in(&self, root: &Root) -> bool {
+        self.root
+            .as_ref()
+            .is_none_or(|r| Path::new(r) == Path::new(&root.path))
+    }
+}
+
+/// Return the package in the title of an ebuild process ('[cat/pf] sandbox ...')
+fn package_from_title(args: &[String]) -> Option<String> {
+    let title = args.first()?.strip_prefix('[')?;
+    let (package, _) = title.split_once(']')?;
+    package.contains('/').then(|| package.to_string())
+}
+
+/// Return the package 'CATEGORY/PF' given by the environment of a process, if both are there
+fn package_from_environ(environ: &[u8]) -> Option<String> {
+    let mut category = None;
+    let mut pf = None;
+    for var in environ.split(|b| *b == 0) {
+        let var = String::from_utf8_lossy(var);
+        if let Some(value) = var.strip_prefix("CATEGORY=") {
+            category = Some(value.to_string());
+        } else if let Some(value) = var.strip_prefix("PF=") {
+            pf = Some(value.to_string());
+        }
+    }
+    Some(format!("{}/{}", category?, pf?))
+}
+
+/// Return true if the command line is the one of emerge (directly, or through python)
+fn is_emerge(args: &[String]) -> bool {
+    args.iter().take(2).any(|arg| {
+        Path::new(arg)
+            .file_name()
+            .is_some_and(|name| name == "emerge")
+    })
+}
+
+/// The identity of a file in /proc/locks: major and minor numbers of the device, and inode
+type FileId = (u64, u64, u64);
+
+/// Return the identity of the file at `path`, as written in /proc/locks
+fn file_id(path: &str) -> Option<FileId> {
+    let metadata = fs::metadata(path).ok()?;
+    let dev = metadata.dev();
+    // The encoding of the device numbers of Linux
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    Some((major, minor, metadata.ino()))
+}
+
+/// Return the files locked in the content of /proc/locks
+///
+/// # Examples
+/// `1: POSIX  ADVISORY  WRITE 1234 08:01:123456 0 EOF` locks the inode 123456 of the device 8:1
+fn parse_locks(content: &str) -> HashSet<FileId> {
+    content
+        .lines()
+        // The processes waiting for a lock have a line starting with '->'
+        .filter(|line| !line.contains("->"))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace().nth(5)?.split(':');
+            let major = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let minor = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let inode = parts.next()?.parse().ok()?;
+            Some((major, minor, inode))
+        })
+        .collect()
+}
+
+/// What is running on this machine, according to /proc
+pub struct Liveness {
+    /// The processes of portage
+    processes: Vec<PortageProcess>,
+    /// The files locked by a process
+    locks: HashSet<FileId>,
+}
+
+impl Liveness {
+    /// Read the processes of portage and the locks in `proc`
+    ///
+    /// Return `None` if `proc` cannot be read (it is not mounted, or we are in a sandbox).
+    pub fn read(proc: &str) -> Option<Self> {
+        let processes = fs::read_dir(proc)
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .filter_map(|pid| PortageProcess::read(proc, pid))
+            .collect();
+        let locks = parse_locks(&fs::read_to_string(format!("{proc}/locks")).ok()?);
+        Some(Self { processes, locks })
+    }
+
+    /// Return the processes of portage running in `root` (and the ones whose root we do not know)
+    pub fn processes_in<'a>(&'a self, root: &'a Root) -> impl Iterator<Item = &'a PortageProcess> {
+        self.processes.iter().filter(move |p| p.is_in(root))
+    }
+
+    /// Return true if `full_name` is being built in `root`
+    ///
+    /// It is, if a process of portage builds it, or if the lock portage takes during the build
+    /// (`<tmpdir>/<cat>/.<pf>.portage_lockfile`) is held.
+    pub fn is_building(&self, full_name: &str, root: &Root) -> bool {
+        if self
+            .processes_in(root)
+            .any(|p| p.package.as_deref() == Some(full_name))
+        {
+            return true;
+        }
+
+        let Some((category, pf)) = full_name.split_once('/') else {
+            return false;
+        };
+        let mut lockfile = String::new();
+        correct_path(
+            &root.paths.tmpdir,
+            &format!("{category}/.{pf}.portage_lockfile"),
+            &mut lockfile,
+        );
+        file_id(&lockfile).is_some_and(|id| self.locks.contains(&id))
+    }
+
+    /// Put in [`LogState::resources`] what each build of `root` uses (see [`Resources::measure`])
+    ///
+    /// * `before`, `after`: Two snapshots of the processes, taken [`SAMPLE_INTERVAL`](crate::resources::SAMPLE_INTERVAL) apart
+    pub fn measure(&self, root: &Root, state: &mut LogState, before: &Snapshot, after: &Snapshot) {
+        for full_name in state.emerges_not_complete.keys() {
+            let pids: Vec<u32> = self
+                .processes_in(root)
+                .filter(|p| p.package.as_deref() == Some(full_name))
+                .map(|p| p.pid)
+                .collect();
+            if !pids.is_empty() {
+                state
+                    .resources
+                    .insert(full_name.clone(), Resources::measure(before, after, &pids));
+            }
+        }
+    }
+
+    /// Remove from `state` the emerges of `root` that are not being built anymore (see [`Liveness::is_building`])
+    ///
+    /// They were killed without writing '*** terminating.' in the log. Nothing is removed while emerge runs in `root`:
+    /// between two phases, or while it fetches or waits for the lock, no process builds the package.
+    pub fn confirm(&self, root: &Root, state: &mut LogState) {
+        if self.processes_in(root).any(|p| p.package.is_none()) {
+            return;
+        }
+        state
+            .emerges_not_complete
+            .retain(|full_name, _| self.is_building(full_name, root));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parse_file::get_info, root::Paths};
+
+    fn root(path: &str) -> Root {
+        let mut root = Root::from_fakeroot(path, &[], &Paths::default());
+        root.paths.tmpdir = "./tests/tmpdir/".to_string();
+        root
+    }
+
+    #[test]
+    fn read_processes() {
+        let liveness = Liveness::read("./tests/proc").unwrap();
+        let mut processes = liveness.processes;
+        processes.sort_by_key(|p| p.pid);
+        assert_eq!(
+            processes,
+            vec![
+                PortageProcess {
+                    pid: 100,
+                    root: Some("/".to_string()),
+                    package: None
+                },
+                PortageProcess {
+                    pid: 200,
+                    root: Some("/".to_string()),
+                    package: Some("app/testing-0.0.0".to_string())
+                },
+                PortageProcess {
+                    pid: 300,
+                    root: Some("/mnt/gentoo".to_string()),
+                    package: Some("sys-devel/gcc-13.3.1".to_string())
+                },
+            ]
+        );
+        assert!(Liveness::read("./tests/do_not_exist").is_none());
+    }
+
+    #[test]
+    fn locks_parse() {
+        let locks = parse_locks(&fs::read_to_string("./tests/proc/locks").unwrap());
+        assert_eq!(locks, HashSet::from([(8, 1, 123456), (0xfd, 2, 42)]));
+    }
+
+    #[test]
+    fn building_in_root() {
+        let liveness = Liveness::read("./tests/proc").unwrap();
+        assert!(liveness.is_building("app/testing-0.0.0", &root("/")));
+        assert!(!liveness.is_building("app/testing-0.0.0", &root("/mnt/gentoo/")));
+        assert!(liveness.is_building("sys-devel/gcc-13.3.1", &root("/mnt/gentoo/")));
+        assert!(!liveness.is_building("sys-devel/gcc-13.3.1", &root("/")));
+    }
+
+    #[test]
+    fn building_with_lock() {
+        let lockfile = "./tests/tmpdir/app/.testing-0.0.0.portage_lockfile";
+        let mut liveness = Liveness {
+            processes: vec![],
+            locks: HashSet::new(),
+        };
+        // The lock file is left behind when emerge is killed
+        assert!(!liveness.is_building("app/testing-0.0.0", &root("/")));
+        liveness.locks.insert(file_id(lockfile).unwrap());
+        assert!(liveness.is_building("app/testing-0.0.0", &root("/")));
+    }
+
+    fn two_emerges() -> LogState {
+        let mut state = LogState::default();
+        for line in [
+            "1234567890:  >>> emerge (1 of 2) app/testing-0.0.0 to /",
+            "1234567890:  >>> emerge (2 of 2) app/killed-1.0 to /",
+        ] {
+            let package = get_info(line).unwrap();
+            state
+                .emerges_not_complete
+                .insert(package.full_name.clone(), package);
+        }
+        state
+    }
+
+    #[test]
+    fn confirm_emerges() {
+        let mut liveness = Liveness::read("./tests/proc").unwrap();
+        // emerge itself was killed, but not the build of app/testing
+        liveness.processes.retain(|p| p.package.is_some());
+        let mut state = two_emerges();
+
+        liveness.confirm(&root("/"), &mut state);
+        let running: Vec<&String> = state.emerges_not_complete.keys().collect();
+        assert_eq!(running, vec!["app/testing-0.0.0"]);
+    }
+
+    #[test]
+    fn confirm_with_emerge_running() {
+        let liveness = Liveness {
+            processes: vec![PortageProcess {
+                pid: 100,
+                root: Some("/".to_string()),
+                package: None,
+            }],
+            locks: HashSet::new(),
+        };
+        // No package is being built, but emerge runs in this root
+        let mut state = two_emerges();
+        liveness.confirm(&root("/"), &mut state);
+        assert_eq!(state.emerges_not_complete.len(), 2);
+
+        liveness.confirm(&root("/mnt/gentoo/"), &mut state);
+        assert!(state.emerges_not_complete.is_empty());
+    }
+
+    #[test]
+    fn measure_builds() {
+        let liveness = Liveness::read("./tests/proc").unwrap();
+        let mut state = LogState::default();
+        let package = get_info("1234567890:  >>> emerge (1 of 1) app/testing-0.0.0 to /").unwrap();
+        state
+            .emerges_not_complete
+            .insert(package.full_name.clone(), package);
+
+        let snapshot = Snapshot::read("./tests/proc");
+        liveness.measure(&root("/mnt/gentoo"), &mut state, &snapshot, &snapshot);
+        assert!(state.resources.is_empty());
+        liveness.measure(&root("/"), &mut state, &snapshot, &snapshot);
+        let resources = &state.resources["app/testing-0.0.0"];
+        // The sandbox, make and two compilers
+        assert_eq!(resources.jobs, 2);
+        assert_eq!(resources.rss, 4096 + 8192 + 524288 + 262144);
+    }
+}
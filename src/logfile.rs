@@ -0,0 +1,458 @@
+#![warn(missing_docs)]
+
+//! Open the logs, compressed or not, and find the ones rotated by logrotate
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::useful::correct_path;
+
+/// The compressions we can read, found from the first bytes of the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+    Bzip2,
+}
+
+impl Compression {
+    /// Find the compression of a file from its first bytes
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Return the compression of `file`, and go back to its start
+fn compression_of(file: &mut File) -> io::Result<Compression> {
+    let mut magic = [0; 6];
+    let read = file.read(&mut magic)?;
+    file.rewind()?;
+    Ok(Compression::detect(&magic[..read]))
+}
+
+/// Return the size of the log at `path`, or `None` if it is compressed or can not be read
+///
+/// Only these logs can be read from the middle (see [`for_each_line_between`]).
+pub fn plain_size(path: &str) -> Option<u64> {
+    let mut file = File::open(path).ok()?;
+    match compression_of(&mut file).ok()? {
+        Compression::None => Some(file.metadata().ok()?.len()),
+        _ => None,
+    }
+}
+
+/// Return the size of the complete lines of the plain log at `path`: the position after its last end of line
+///
+/// A log being written can end in the middle of a line. Return `None` if the log is compressed or can not be read.
+pub fn complete_size(path: &str) -> Option<u64> {
+    const BLOCK: u64 = 4096;
+
+    let size = plain_size(path)?;
+    let mut file = File::open(path).ok()?;
+    let mut position = size;
+    while position > 0 {
+        let start = position.saturating_sub(BLOCK);
+        let mut block = vec![0; (position - start) as usize];
+        file.seek(SeekFrom::Start(start)).ok()?;
+        file.read_exact(&mut block).ok()?;
+        if let Some(i) = block.iter().rposition(|&c| c == b'\n') {
+            return Some(start + i as u64 + 1);
+        }
+        position = start;
+    }
+    Some(0)
+}
+
+/// Return the position of the first line starting at or after `from` in the plain log at `path`
+/// for which `is_start(previous_line, line)` is true, or `None` if there is none
+pub fn find_line_start(
+    path: &str,
+    from: u64,
+    is_start: impl Fn(&str, &str) -> bool,
+) -> io::Result<Option<u64>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut position = 0;
+    let mut buffer = Vec::new();
+    // Go to the start of the first complete line
+    if from > 0 {
+        reader.seek(SeekFrom::Start(from - 1))?;
+        position = from - 1 + reader.read_until(b'\n', &mut buffer)? as u64;
+    }
+
+    let mut previous = String::new();
+    loop {
+        buffer.clear();
+        let read = reader.read_until(b'\n', &mut buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let line = decode_line(&buffer);
+        if is_start(&previous, &line) {
+            return Ok(Some(position));
+        }
+        previous = line.into_owned();
+        position += read as u64;
+    }
+}
+
+/// Call `action` on each line of the plain log at `path` between the bytes `start` and `end`
+///
+/// `start` and `end` must be the start of a line (or the end of the file).
+pub fn for_each_line_between(
+    path: &str,
+    start: u64,
+    end: u64,
+    action: impl FnMut(&str),
+) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    for_each_line(BufReader::new(file.take(end - start)), action)
+}
+
+/// The extensions given by logrotate to the compressed logs
+const EXTENSIONS: [&str; 4] = [".gz", ".xz", ".zst", ".bz2"];
+
+/// Open the log at `path`, and decompress it if it is compressed with gzip, xz, zstd or bzip2
+///
+/// The compression is found from the content of the file, not from its name.
+/// The file is read as it is needed, use [`for_each_line`] to go through it.
+pub fn open_log(path: &str) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let compression = compression_of(&mut file)?;
+    let file = BufReader::new(file);
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(file))),
+        Compression::Xz => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(
+            file,
+        ))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(
+            file,
+        )?)),
+        Compression::Bzip2 => Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(file))),
+    })
+}
+
+/// Remove the end of line (`\n` or `\r\n`) of `line`, and replace the invalid UTF-8 bytes
+fn decode_line(line: &[u8]) -> std::borrow::Cow<'_, str> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line)
+}
+
+/// Call `action` on each line read from `reader`, without keeping more than one line in memory
+///
+/// The invalid UTF-8 bytes are replaced by `U+FFFD`, so a corrupted line does not stop the reading.
+pub fn for_each_line(mut reader: impl BufRead, mut action: impl FnMut(&str)) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    loop {
+        buffer.clear();
+        if reader.read_until(b'\n', &mut buffer)? == 0 {
+            return Ok(());
+        }
+        action(&decode_line(&buffer));
+    }
+}
+
+/// Return the path of the build log of `full_name` started at `start` in `build_log`, if it exists
+///
+/// Portage names them `<category>/<pf>:<date>.log` (that is why you need split-log in your FEATURE variable).
+pub fn build_log_path(build_log: &str, full_name: &str, start: u32) -> Option<String> {
+    let mut log_emerge = String::new();
+    correct_path(build_log, full_name, &mut log_emerge);
+
+    // Test 3 files, as there may be slight delay between when the line was written in emerge.log, and when the file was created
+    [start + 1, start, start.saturating_sub(1)]
+        .into_iter()
+        .map(|time| {
+            let datetime = chrono::DateTime::from_timestamp(time.into(), 0).unwrap();
+            format!("{log_emerge}:{}.log", datetime.format("%Y%m%d-%H%M%S"))
+        })
+        .find(|path| Path::new(path).exists())
+}
+
+/// Return the last line of the file at `path`, reading only the end of the file
+///
+/// As for [`str::lines`], a final end of line does not start a new line. Return `None` if the file can not be read.
+pub fn last_line(path: &str) -> Option<String> {
+    const BLOCK: u64 = 4096;
+
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    // Read blocks from the end until the start of the last line is found
+    let mut end = Vec::new();
+    let mut position = size;
+    while position > 0 {
+        let start = position.saturating_sub(BLOCK);
+        let mut block = vec![0; (position - start) as usize];
+        file.seek(SeekFrom::Start(start)).ok()?;
+        file.read_exact(&mut block).ok()?;
+        block.extend_from_slice(&end);
+        end = block;
+        position = start;
+
+        let content = end.strip_suffix(b"\n").unwrap_or(&end);
+        if let Some(i) = content.iter().rposition(|&c| c == b'\n') {
+            return Some(decode_line(&content[i + 1..]).into_owned());
+        }
+    }
+    Some(decode_line(&end).into_owned())
+}
+
+/// Return the position of the end of the last line of the plain log at `path` for which `is_wanted` is true,
+/// reading the file from its end, or `None` if there is none
+///
+/// The position is the one of the start of the next line.
+pub fn rfind_line(path: &str, is_wanted: impl Fn(&str) -> bool) -> io::Result<Option<u64>> {
+    const BLOCK: u64 = 64 * 1024;
+
+    let mut file = File::open(path)?;
+    let mut position = file.metadata()?.len();
+    // The start of the line that begins before `position`, not checked yet
+    let mut carry = Vec::new();
+    loop {
+        let start = position.saturating_sub(BLOCK);
+        let mut data = vec![0; (position - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut data)?;
+        data.extend_from_slice(&carry);
+
+        // Check the complete lines from the last one, `end` is the end of the line checked (with its end of line)
+        let mut end = data.len();
+        loop {
+            let content_end = if data[..end].ends_with(b"\n") {
+                end - 1
+            } else {
+                end
+            };
+            let line_start = match data[..content_end].iter().rposition(|&c| c == b'\n') {
+                Some(i) => i + 1,
+                // The first line of the file is complete
+                None if start == 0 => 0,
+                None => break,
+            };
+            if (line_start < end) && is_wanted(&decode_line(&data[line_start..end])) {
+                return Ok(Some(start + end as u64));
+            }
+            if line_start == 0 {
+                return Ok(None);
+            }
+            end = line_start;
+        }
+        carry = data[..end].to_vec();
+        position = start;
+    }
+}
+
+/// Return the position of a rotated log from the end of its name (`emerge.log` excluded)
+///
+/// The suffixes can be a number (`.1`, `.2.gz`, the higher is the oldest) or a date (`-20240930.xz`, with dateext).
+/// The oldest logs are first, with the dates before the numbers.
+fn rotation(suffix: &str) -> Option<(bool, i64)> {
+    let suffix = EXTENSIONS
+        .iter()
+        .find_map(|ext| suffix.strip_suffix(ext))
+        .unwrap_or(suffix);
+    if let Some(number) = suffix.strip_prefix('.') {
+        let number: i64 = number.parse().ok()?;
+        return Some((true, -number));
+    }
+    let date = suffix.strip_prefix('-')?;
+    if date.is_empty() || !date.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((false, date.parse().ok()?))
+}
+
+/// Return the rotated logs of `path` found in its folder, from the oldest to the newest, followed by `path`
+///
+/// `emerge.log.1`, `emerge.log.2.gz` or `emerge.log-20240930.xz` are rotated logs of `emerge.log`.
+pub fn with_rotated(path: &str) -> Vec<String> {
+    let log = Path::new(path);
+    let (Some(folder), Some(name)) = (log.parent(), log.file_name().and_then(|n| n.to_str()))
+    else {
+        return vec![path.to_string()];
+    };
+    let folder = if folder.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        folder
+    };
+
+    let mut rotated: Vec<((bool, i64), String)> = fs::read_dir(folder)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name();
+            let suffix = file_name.to_str()?.strip_prefix(name)?;
+            let position = rotation(suffix)?;
+            let rotated_path = log.with_file_name(&file_name);
+            Some((position, rotated_path.to_str()?.to_string()))
+        })
+        .collect();
+    rotated.sort();
+
+    let mut logs: Vec<String> = rotated.into_iter().map(|(_, p)| p).collect();
+    logs.push(path.to_string());
+    logs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(Compression::detect(&[0x1f, 0x8b, 8]), Compression::Gzip);
+        assert_eq!(Compression::detect(b"BZh91AY"), Compression::Bzip2);
+        assert_eq!(Compression::detect(b"12345"), Compression::None);
+        assert_eq!(Compression::detect(b""), Compression::None);
+    }
+
+    #[test]
+    fn rotation_suffix() {
+        assert_eq!(rotation(".1"), Some((true, -1)));
+        assert_eq!(rotation(".12.zst"), Some((true, -12)));
+        assert_eq!(rotation("-20240930.xz"), Some((false, 20240930)));
+        assert!(rotation(".old").is_none());
+        assert!(rotation("").is_none());
+        assert!(rotation(".gz").is_none());
+    }
+
+    #[test]
+    fn read_compressed() {
+        for file in [
+            "emerge.log.2.gz",
+            "emerge.log.3.xz",
+            "emerge.log.4.zst",
+            "emerge.log.5.bz2",
+        ] {
+            let mut lines = Vec::new();
+            let reader = open_log(&format!("./tests/rotated/{file}")).unwrap();
+            for_each_line(reader, |l| lines.push(l.to_string())).unwrap();
+            assert!(lines[0].starts_with("1234"), "{file}");
+            assert_eq!(lines.len(), 6, "{file}");
+        }
+    }
+
+    #[test]
+    fn lines_lossy() {
+        let content: &[u8] = b"first\r\nsec\xffond\n\nlast";
+        let mut lines = Vec::new();
+        for_each_line(content, |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(lines, ["first", "sec\u{fffd}ond", "", "last"]);
+    }
+
+    #[test]
+    fn read_between() {
+        let path = "./tests/emerge.log/sessions";
+        assert!(plain_size("./tests/rotated/emerge.log.2.gz").is_none());
+        let size = plain_size(path).unwrap();
+
+        let is_session = |_: &str, line: &str| line.contains("Started emerge on:");
+        let second = find_line_start(path, 1, is_session).unwrap().unwrap();
+        let third = find_line_start(path, second + 1, is_session)
+            .unwrap()
+            .unwrap();
+        assert!(find_line_start(path, third + 1, is_session)
+            .unwrap()
+            .is_none());
+
+        let mut lines = Vec::new();
+        for_each_line_between(path, second, third, |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(lines.len(), 7);
+        assert!(lines[0].ends_with("Started emerge on: Feb 13, 2009 23:30:20"));
+        lines.clear();
+        for_each_line_between(path, third, size, |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn find_last_line() {
+        let path = "./tests/emerge.log/sessions";
+        let is_term = |l: &str| l.ends_with("*** terminating.");
+        let position = rfind_line(path, is_term).unwrap().unwrap();
+        let mut lines = Vec::new();
+        let size = plain_size(path).unwrap();
+        for_each_line_between(path, position, size, |l| lines.push(l.to_string())).unwrap();
+        assert_eq!(lines.len(), 3);
+
+        let first = rfind_line(path, |l| l.contains("23:30:00"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(first, 53);
+        assert!(rfind_line(path, |l| l.contains("nothing"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn size_of_complete_lines() {
+        assert_eq!(complete_size("./tests/emerge.log/partial"), Some(97));
+        let size = plain_size("./tests/emerge.log/sessions");
+        assert_eq!(complete_size("./tests/emerge.log/sessions"), size);
+        assert!(complete_size("./tests/rotated/emerge.log.3.xz").is_none());
+    }
+
+    #[test]
+    fn read_last_line() {
+        assert_eq!(
+            last_line("./tests/rotated/emerge.log").unwrap(),
+            "1234505060:  *** terminating."
+        );
+        assert!(last_line("./tests/do_not_exist").is_none());
+    }
+
+    #[test]
+    fn find_rotated() {
+        let logs = with_rotated("./tests/rotated/emerge.log");
+        assert_eq!(
+            logs,
+            [
+                "./tests/rotated/emerge.log.5.bz2",
+                "./tests/rotated/emerge.log.4.zst",
+                "./tests/rotated/emerge.log.3.xz",
+                "./tests/rotated/emerge.log.2.gz",
+                "./tests/rotated/emerge.log.1",
+                "./tests/rotated/emerge.log",
+            ]
+        );
+        assert_eq!(with_rotated("/do/not/exist"), ["/do/not/exist"]);
+    }
+}
@@ -24,79 +24,435 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 // // // // // // // // // // // // // // // // // // // // // // // //
 
-use std::collections::HashMap;
+use std::io::{BufWriter, Write};
 
 use clap::Parser;
 
-/// Read emerge log from file and put output in print.
+/// Put the status of the emerges of a root in print.
 ///
-/// * `file`: The file from which to create the record of past emerge.
+/// * `root`: The root, with the paths to its files
+/// * `state`: What we learned from the logs of `root` (see [`genlogsum::read_roots_running`])
+/// * `resume`: The packages the emerge will merge, or `None` to read them from mtimedb (see [`genlogsum::read_resume`])
+/// * `references`: The histories of the other machines
 /// * `config`: The configuration of the running program
-/// * `fakeroot`: The root we will use to search and read mtimedb
+/// * `clock`: Gives the current time (see [`genlogsum::Arguments::clock`])
 /// * `print`: A string that will be modified to contains the status
-/// * return an error if there was a problem when reading `file`
+/// * return the total time needed for this root, or `None` if nothing is emerging, syncing or unmerging in it
 ///
-/// The reading of `[root]/var/cache/db/mtimedb` is done in this function, meaning if you used `--all` and told the program to read multiple files (using `--files`) from multiple root (with `--fakeroot`), your output will be polluted by it.
-fn emerge_file(
-    file: &str,
+/// The histories of all the files of `root` are merged before computing anything, and mtimedb is read only once.
+fn emerge_root(
+    root: &genlogsum::Root,
+    mut state: genlogsum::LogState,
+    resume: Option<Vec<genlogsum::EmergeResume>>,
+    references: &[genlogsum::Reference],
     config: &genlogsum::Arguments,
-    fakeroot: &str,
+    clock: &dyn genlogsum::Clock,
     print: &mut String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut emerges_not_complete: HashMap<String, genlogsum::PackageInfo> = HashMap::new();
-    let mut completed_atoms: HashMap<String, genlogsum::Atom> = HashMap::new();
+) -> Option<f64> {
+    genlogsum::set_last_time(&state.emerges_not_complete, &mut state.completed_atoms);
 
-    genlogsum::read_file(file, &mut emerges_not_complete, &mut completed_atoms)?;
-    genlogsum::set_last_time(&emerges_not_complete, &mut completed_atoms);
+    if state.emerges_not_complete.is_empty()
+        && state.syncs_not_complete.is_empty()
+        && state.unmerges_not_complete.is_empty()
+    {
+        return None;
+    }
 
-    if emerges_not_complete.is_empty() {
-        return Ok(());
+    genlogsum::get_unmerges(&state, config, root, clock, print);
+    let mut total = genlogsum::get_syncs(&state, config, root, clock, print);
+    if !state.emerges_not_complete.is_empty() {
+        let resume = resume.unwrap_or_else(|| genlogsum::read_resume(config, root));
+        let t = genlogsum::get_emerges(&mut state, references, config, root, &resume, clock, print);
+        total = genlogsum::add_time(total, t);
     }
 
-    genlogsum::get_emerges(
-        &emerges_not_complete,
-        &mut completed_atoms,
-        config,
-        fakeroot,
-        print,
-    );
-    Ok(())
+    Some(total)
 }
 
-/// Go the root and read all files given by the config
+/// Print the status of the emerges of every root (see [`emerge_root`]), or that nothing is running
 ///
-/// * `fakeroot`: The folder we will use as root for the subsequent search for files
+/// * `roots`: The roots, with what we learned from them and the packages they will merge
+/// * `references`: The histories of the other machines
 /// * `config`: The configuration of the running program
-/// * `print`: A string that will be modified to contains the status
+/// * `clock`: Gives the current time
+fn print_status(
+    roots: impl Iterator<
+        Item = (
+            genlogsum::Root,
+            genlogsum::LogState,
+            Option<Vec<genlogsum::EmergeResume>>,
+        ),
+    >,
+    references: &[genlogsum::Reference],
+    config: &genlogsum::Arguments,
+    clock: &dyn genlogsum::Clock,
+) {
+    let mut print = String::new();
+    let mut grand_total = 0.0;
+    let mut num_roots = 0;
+    for (root, state, resume) in roots {
+        if let Some(total) =
+            emerge_root(&root, state, resume, references, config, clock, &mut print)
+        {
+            grand_total = genlogsum::add_time(grand_total, total);
+            num_roots += 1;
+        }
+    }
+
+    if config.format.all && (num_roots > 1) {
+        print.push_str(&genlogsum::format_total("Grand total", grand_total));
+    }
+
+    if print.is_empty() {
+        println!("Not currently emerging");
+    } else {
+        // There is a newline at the end of print
+        print!("{print}");
+    }
+}
+
+/// Print how fast each reference is compared to each root
 ///
-/// Something to note: if you are using `--all` for the program, the reading of `var/cache/db/mtimedb` is __NOT__ done in this function.
-fn emerge_fakeroot(fakeroot: &str, config: &genlogsum::Arguments, print: &mut String) {
-    for file in &config.files {
-        let mut path = String::new();
-        genlogsum::correct_path(fakeroot, file, &mut path);
-        if let Err(e) = emerge_file(&path, config, fakeroot, print) {
-            if !config.skip_file {
-                eprintln!("Application error: {e} for {path}");
+/// * `references`: The histories of the other machines
+/// * `config`: The configuration of the running program
+fn calibrate(references: &[genlogsum::Reference], config: &genlogsum::Arguments) {
+    if references.is_empty() {
+        eprintln!("Nothing to calibrate, use --reference to give the history of another machine");
+        return;
+    }
+
+    let roots = config.get_roots();
+    for (root, state) in roots
+        .iter()
+        .zip(genlogsum::read_roots(&roots, config.skip_file))
+    {
+        let local = if root.name.is_empty() {
+            "this machine"
+        } else {
+            &root.name
+        };
+        for reference in references {
+            match genlogsum::calibrate(&state.completed_atoms, &reference.atoms) {
+                Some(c) => println!("{}", c.describe(&reference.source.name, local)),
+                None => println!(
+                    "{} and {local} have no package in common",
+                    reference.source.name
+                ),
             }
         }
     }
 }
 
+/// Write all the completed merges of every root on the standard output
+///
+/// * `format`: The format of the output
+/// * `config`: The configuration of the running program
+///
+/// The logs are read one by one, so that we know where each merge comes from.
+fn export(format: genlogsum::ExportFormat, config: &genlogsum::Arguments) {
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    let mut result = match format.header() {
+        Some(header) => out.write_all(header.as_bytes()),
+        None => Ok(()),
+    };
+
+    for root in config.get_roots() {
+        let mut state = genlogsum::LogState::default();
+        for path in &root.logs {
+            let before = state.merges.len();
+            genlogsum::read_log(path, config.skip_file, &mut state);
+            for merge in &state.merges[before..] {
+                result = result.and_then(|_| {
+                    out.write_all(format.format(merge, root.label(), path).as_bytes())
+                });
+            }
+        }
+    }
+
+    // Stop silently if the output was closed (for example when piped to head)
+    let _ = result.and_then(|_| out.flush());
+}
+
+/// Read every root, and call `show` with the root and what we learned from it
+///
+/// The roots are read at the same time, but shown in order.
+/// A header with the name of the root is printed before each root if there are more than one.
+fn for_each_root(
+    config: &genlogsum::Arguments,
+    mut show: impl FnMut(&genlogsum::Root, &mut genlogsum::LogState),
+) {
+    let roots = config.get_roots();
+    let mut states = genlogsum::read_roots(&roots, config.skip_file);
+    for (root, state) in roots.iter().zip(&mut states) {
+        if roots.len() > 1 {
+            println!("[{}]", root.label());
+        }
+        show(root, state);
+    }
+}
+
+/// Read what is running in every root (see [`genlogsum::read_roots_running`]), and check it in /proc (see [`check_running`])
+fn running_states(
+    roots: &[genlogsum::Root],
+    config: &genlogsum::Arguments,
+    measure: bool,
+) -> Vec<genlogsum::LogState> {
+    let mut states = genlogsum::read_roots_running(roots, config);
+    check_running(roots, config, measure, &mut states);
+    states
+}
+
+/// Check in /proc what is running in `states`, read from `roots`
+///
+/// Unless `--no-proc` or `--now` is used, the emerges that are not running anymore are removed (see [`genlogsum::Liveness::confirm`]).
+/// If `measure`, the resources used by each build are measured too (see [`genlogsum::Liveness::measure`]).
+fn check_running(
+    roots: &[genlogsum::Root],
+    config: &genlogsum::Arguments,
+    measure: bool,
+    states: &mut [genlogsum::LogState],
+) {
+    // The processes only tell what runs now, not at the time given with --now
+    if config.no_proc || config.now.is_some() {
+        return;
+    }
+    let Some(liveness) = genlogsum::Liveness::read("/proc") else {
+        return;
+    };
+
+    for (root, state) in roots.iter().zip(states.iter_mut()) {
+        liveness.confirm(root, state);
+    }
+    if measure && states.iter().any(|s| !s.emerges_not_complete.is_empty()) {
+        let before = genlogsum::Snapshot::read("/proc");
+        std::thread::sleep(genlogsum::SAMPLE_INTERVAL);
+        let after = genlogsum::Snapshot::read("/proc");
+        for (root, state) in roots.iter().zip(states.iter_mut()) {
+            liveness.measure(root, state, &before, &after);
+        }
+    }
+}
+
+/// Print the status every `interval` seconds, and record the peak memory of the builds that complete
+///
+/// The logs are read once, then only the lines added to them (see [`genlogsum::Watcher`]).
+/// The resources are always measured, to know the peaks, but only shown with `--resources`.
+fn watch(
+    interval: u64,
+    hook: Option<&str>,
+    notify: bool,
+    references: &[genlogsum::Reference],
+    config: &genlogsum::Arguments,
+    clock: &dyn genlogsum::Clock,
+) {
+    let roots = config.get_roots();
+    let mut watchers: Vec<genlogsum::Watcher> = roots
+        .iter()
+        .map(|root| genlogsum::Watcher::new(root, config.skip_file))
+        .collect();
+    // The errors are reported as the ones of the logs, unless --skip-file
+    let skip = config.skip_file;
+    loop {
+        for (root, watcher) in roots.iter().zip(&mut watchers) {
+            let Some(changes) = genlogsum::report_error(watcher.update(root), root.label(), skip)
+            else {
+                continue;
+            };
+            let now = clock.now();
+            let mut transitions = vec![];
+            for e in &changes.ended {
+                if let (Some(merge), Some(peak)) = (&e.merge, e.peak_rss) {
+                    let saved = genlogsum::save_peak(&root.paths.metrics, merge, peak);
+                    genlogsum::report_error(saved, &root.paths.metrics, skip);
+                }
+                transitions.push(genlogsum::Transition::package(e, root, now));
+            }
+            // The mergelist ends after its last package
+            for session in &changes.sessions {
+                transitions.push(genlogsum::Transition::mergelist(session, root, now));
+            }
+
+            for transition in &transitions {
+                if let Some(command) = hook {
+                    genlogsum::report_error(transition.run(command), command, skip);
+                }
+                if notify {
+                    genlogsum::report_error(transition.notify(), "--notify", skip);
+                }
+            }
+        }
+
+        // What the watchers know is shown, without reading the logs again
+        let mut states: Vec<genlogsum::LogState> =
+            watchers.iter().map(|w| w.state.clone()).collect();
+        check_running(&roots, config, true, &mut states);
+        for (watcher, state) in watchers.iter_mut().zip(&mut states) {
+            watcher.record_peaks(&state.resources);
+            if !config.resources {
+                state.resources.clear();
+            }
+        }
+
+        // Clear the terminal before printing
+        print!("\x1b[2J\x1b[H");
+        let status = roots.iter().cloned().zip(states).map(|(r, s)| (r, s, None));
+        print_status(status, references, config, clock);
+        std::thread::sleep(std::time::Duration::from_secs(interval));
+    }
+}
+
 /// The main function
 ///
-/// This function only parse the arguments, call [`emerge_fakeroot`], and print the output.
+/// This function only parse the arguments, call [`emerge_root`] (or the function of the command), and print the output.
 fn main() {
     let args = &genlogsum::Arguments::parse();
-    let mut print = String::new();
+    let references = args.load_references();
+    let clock = args.clock();
 
-    for fakeroot in &args.fakeroots {
-        emerge_fakeroot(fakeroot, args, &mut print);
+    match args.command {
+        Some(genlogsum::Command::Calibrate) => return calibrate(&references, args),
+        Some(genlogsum::Command::Export { format }) => return export(format, args),
+        Some(genlogsum::Command::Replay { at }) => {
+            // The packages emerged after `at` replace mtimedb, that only knows the present
+            let roots = args.get_roots().into_iter().map(|root| {
+                let replay = genlogsum::replay_root(&root, args.skip_file, at);
+                let resume = replay.resume();
+                (root, replay.state, Some(resume))
+            });
+            return print_status(roots, &references, args, &genlogsum::FixedClock(at));
+        }
+        Some(genlogsum::Command::Sessions) => {
+            let now = clock.now();
+            return for_each_root(args, |_, state| {
+                for session in &state.sessions {
+                    println!("{}", session.summary(now));
+                }
+            });
+        }
+        Some(genlogsum::Command::History {
+            ref package,
+            rebuilds,
+        }) => {
+            return for_each_root(args, |_, state| {
+                let entries: Vec<genlogsum::HistoryEntry> =
+                    genlogsum::history(&state.merges, &state.unmerges)
+                        .into_iter()
+                        .filter(|e| package.as_ref().is_none_or(|p| e.is_about(p)))
+                        .filter(|e| args.keep_repository(&e.repository))
+                        .collect();
+                if rebuilds {
+                    for (full_name, count, last) in genlogsum::rebuilds(&entries) {
+                        println!(
+                            "{full_name}  {count} rebuild{}, last {}",
+                            if count > 1 { "s" } else { "" },
+                            genlogsum::format_date(last)
+                        );
+                    }
+                } else {
+                    for entry in entries {
+                        println!("{}", entry.line());
+                    }
+                }
+            });
+        }
+        Some(genlogsum::Command::Watch {
+            interval,
+            ref hook,
+            #[cfg(feature = "notify")]
+            notify,
+        }) => {
+            // Without the feature notify, the flag does not exist
+            #[cfg(not(feature = "notify"))]
+            let notify = false;
+            return watch(
+                interval,
+                hook.as_deref(),
+                notify,
+                &references,
+                args,
+                clock.as_ref(),
+            );
+        }
+        Some(genlogsum::Command::Stats { ref package }) => {
+            let memory = genlogsum::total_memory("/proc");
+            return for_each_root(args, |root, state| {
+                state.read_metrics(&root.paths.metrics, &root.paths.build_log);
+                let mut atoms: Vec<&genlogsum::Atom> = state
+                    .completed_atoms
+                    .values()
+                    .filter(|a| package.as_ref().is_none_or(|p| a.is_about(p)))
+                    .filter(|a| args.keep_repository(&a.repository))
+                    .collect();
+                atoms.sort_by_key(|a| a.key());
+                for atom in &atoms {
+                    println!("{}", atom.stats_line());
+                    let slots = atom.known_slots();
+                    if slots.len() > 1 {
+                        // One line per slot, without the name of the package
+                        for (slot, in_slot) in slots
+                            .into_iter()
+                            .filter_map(|s| atom.for_slot(s).map(|a| (s, a)))
+                        {
+                            let line = in_slot.stats_line();
+                            let rest = line.split_once("  ").map_or("", |(_, r)| r);
+                            println!("  :{slot}  {rest}");
+                        }
+                    }
+                }
+                // The packages that could fill the memory of this machine
+                for atom in &atoms {
+                    if let Some(warning) = memory.and_then(|m| atom.memory_warning(m)) {
+                        println!("Warning: {warning}");
+                    }
+                }
+            });
+        }
+        Some(genlogsum::Command::Backtest { ref package }) => {
+            return for_each_root(args, |_, state| {
+                let result = genlogsum::backtest(&state.merges, |m| {
+                    package.as_ref().is_none_or(|p| m.is_about(p))
+                        && args.keep_repository(&m.repository)
+                });
+                let print = |name: &str, accuracies: &[genlogsum::Accuracy]| {
+                    let count = accuracies.first().map_or(0, |a| a.count());
+                    if count == 0 {
+                        return;
+                    }
+                    println!(
+                        "{name}  {count} prediction{}",
+                        if count > 1 { "s" } else { "" }
+                    );
+                    for (estimator, accuracy) in genlogsum::Estimator::ALL.iter().zip(accuracies) {
+                        println!("  {}", accuracy.line(*estimator));
+                    }
+                };
+                print("All packages", &result.overall);
+                for (key, accuracies) in &result.packages {
+                    print(key, accuracies);
+                }
+            });
+        }
+        Some(genlogsum::Command::SyncHistory) => {
+            return for_each_root(args, |_, state| {
+                for sync in &state.syncs {
+                    println!("{}", sync.history_line());
+                }
+            });
+        }
+        Some(genlogsum::Command::Session { id }) => {
+            let now = clock.now();
+            return for_each_root(args, |_, state| {
+                match state.sessions.iter().find(|s| s.id == id) {
+                    Some(session) => print!("{}", session.details(&state.merges, now)),
+                    None => eprintln!("No session {id}"),
+                }
+            });
+        }
+        None => (),
     }
 
-    if print.is_empty() {
-        println!("Not currently emerging");
-    } else {
-        // There is a newline at the end of print
-        print!("{print}");
-    }
+    let roots = args.get_roots();
+    let states = running_states(&roots, args, args.resources);
+    let roots = roots.into_iter().zip(states).map(|(r, s)| (r, s, None));
+    print_status(roots, &references, args, clock.as_ref());
 }
@@ -0,0 +1,109 @@
+#![warn(missing_docs)]
+
+//! Record the peak memory of the builds seen by `gls watch`, to show it in `gls stats`
+//!
+//! The file has one line for each completed build: `key<TAB>full name<TAB>start<TAB>peak in kB`.
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::package::Merge;
+
+/// Return the line recording that the build of `merge` needed `peak` kB of memory
+fn metric_line(merge: &Merge, peak: u64) -> String {
+    format!(
+        "{}\t{}-{}\t{}\t{peak}\n",
+        merge.key(),
+        merge.cpn,
+        merge.version,
+        merge.time
+    )
+}
+
+/// Add to the file at `path` that the build of `merge` needed `peak` kB of memory
+///
+/// The folder of the file is created if needed.
+pub fn save_peak(path: &str, merge: &Merge, peak: u64) -> io::Result<()> {
+    if let Some(folder) = Path::new(path).parent() {
+        fs::create_dir_all(folder)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(metric_line(merge, peak).as_bytes())
+}
+
+/// Return the peak memory of the last build of each package recorded in the file at `path`, by key of history
+///
+/// The file is missing until `gls watch` sees a build complete, so nothing is reported if it cannot be read.
+pub fn read_peaks(path: &str) -> HashMap<String, u64> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let key = fields.next()?;
+            let peak = fields.nth(2)?.parse().ok()?;
+            Some((key.to_string(), peak))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_of_metric() {
+        let merge = Merge {
+            time: 1234567890,
+            cpn: "www-client/chromium".to_string(),
+            version: "130.0".to_string(),
+            is_binary: false,
+            duration: 36000,
+            num: "1 of 1".to_string(),
+            session: 1,
+            target_root: "/".to_string(),
+            repository: String::new(),
+            slot: String::new(),
+        };
+        assert_eq!(
+            metric_line(&merge, 14680064),
+            "www-client/chromium\twww-client/chromium-130.0\t1234567890\t14680064\n"
+        );
+    }
+
+    #[test]
+    fn peaks_read() {
+        let peaks = read_peaks("./tests/metrics");
+        assert_eq!(peaks.len(), 2);
+        // The last build wins
+        assert_eq!(peaks["www-client/chromium"], 14680064);
+        assert_eq!(peaks["app-misc/foo::guru"], 20480);
+        assert!(read_peaks("./tests/do_not_exist").is_empty());
+    }
+}
@@ -21,9 +21,17 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 // // // // // // // // // // // // // // // // // // // // // // // //
 
-use crate::useful::{current_time, Over};
+use std::collections::HashMap;
+
+use crate::{
+    atom::{split_cpv, Version},
+    clock::Clock,
+    resources::format_memory,
+    useful::{format_duration, Over},
+};
 
 /// A structure to store the data until we find a line that allows us to either discard it, or add it to the list of Atoms
+#[derive(Clone)]
 pub struct PackageInfo {
     /// The category of the package
     pub category: String,
@@ -37,6 +45,12 @@ pub struct PackageInfo {
     pub is_binary: bool,
     /// The number (x of y)
     pub num: String,
+    /// The root the package is emerged to (`/` unless `--root` is used), with a trailing slash
+    pub target_root: String,
+    /// The repository of the ebuild (empty until a line gives the path of the ebuild, or if binary)
+    pub repository: String,
+    /// The slot of the package (empty if unknown)
+    pub slot: String,
 }
 
 impl PackageInfo {
@@ -44,9 +58,152 @@ impl PackageInfo {
     pub fn cpn(&self) -> String {
         format!("{}/{}", self.category, self.name)
     }
+
+    /// Return the version of the package (everything after the cpn in the full name)
+    pub fn version(&self) -> String {
+        let size = self.category.len() + self.name.len() + 2;
+        self.full_name.get(size..).unwrap_or("").to_string()
+    }
+
+    /// Return the key of the history of the package, see [`history_key`]
+    pub fn key(&self) -> String {
+        history_key(&self.cpn(), &self.repository, &self.target_root)
+    }
+}
+
+/// Return the key of the history of `cpn` from `repository` emerged to `target_root`
+///
+/// The packages emerged to another root (cross-compilation, stage build) have their own history,
+/// so that they do not share their times with the native ones.
+/// The same goes for the packages of an overlay, as their ebuild can be very different from the one of gentoo.
+///
+/// # Examples
+/// `sys-devel/gcc`, `app-misc/foo::guru` or `sys-devel/gcc to /usr/aarch64-linux-gnu/`
+pub fn history_key(cpn: &str, repository: &str, target_root: &str) -> String {
+    let mut key = cpn.to_string();
+    if !repository.is_empty() && (repository != "gentoo") {
+        key.push_str(&format!("::{repository}"));
+    }
+    if target_root != "/" {
+        key.push_str(&format!(" to {target_root}"));
+    }
+    key
+}
+
+/// Return true if `cpn` is the package `filter`, given as category/name or only as name
+pub fn cpn_is_about(cpn: &str, filter: &str) -> bool {
+    cpn == filter || cpn.split_once('/').map(|(_, name)| name) == Some(filter)
+}
+
+/// Add a trailing slash to `target_root` if it does not have one, as portage does
+pub fn normalize_root(target_root: &str) -> String {
+    if target_root.ends_with('/') {
+        target_root.to_string()
+    } else {
+        format!("{target_root}/")
+    }
+}
+
+/// How a merge changes the version of a package, compared to its previous merge
+#[derive(Clone, Debug, PartialEq)]
+pub enum MergeKind {
+    /// The package was never merged before
+    New,
+    /// The version is greater than the previous one (given)
+    Upgrade(String),
+    /// The version is lower than the previous one (given)
+    Downgrade(String),
+    /// The version is the same as the previous one
+    Rebuild,
+}
+
+impl MergeKind {
+    /// Compare `version` with the version of the previous merge of the package, if there is one
+    ///
+    /// If one of the versions can not be parsed (see [`Version`]), a different version is counted as an upgrade.
+    pub fn classify(previous: Option<&str>, version: &str) -> Self {
+        let previous = match previous {
+            Some(p) => p,
+            None => return MergeKind::New,
+        };
+        match (previous.parse::<Version>(), version.parse::<Version>()) {
+            (Ok(p), Ok(v)) if v < p => MergeKind::Downgrade(previous.to_string()),
+            (Ok(p), Ok(v)) if v == p => MergeKind::Rebuild,
+            _ if previous == version => MergeKind::Rebuild,
+            _ => MergeKind::Upgrade(previous.to_string()),
+        }
+    }
+
+    /// Return a short description of the kind
+    ///
+    /// # Examples
+    /// `new`, `upgrade from 1.2.3`, `downgrade from 1.2.3` or `rebuild`
+    pub fn describe(&self) -> String {
+        match self {
+            MergeKind::New => "new".to_string(),
+            MergeKind::Upgrade(from) => format!("upgrade from {from}"),
+            MergeKind::Downgrade(from) => format!("downgrade from {from}"),
+            MergeKind::Rebuild => "rebuild".to_string(),
+        }
+    }
+}
+
+/// A completed merge, as read from the log
+#[derive(Clone, Debug, PartialEq)]
+pub struct Merge {
+    /// The time the emerge was started
+    pub time: u32,
+    /// The category/package_name representation of the package
+    pub cpn: String,
+    /// The version of the package (with the revision)
+    pub version: String,
+    /// Is it a binary emerge ?
+    pub is_binary: bool,
+    /// The time it took to emerge the package
+    pub duration: u32,
+    /// The number (x of y)
+    pub num: String,
+    /// The emerge session the merge belongs to (the number of `Started emerge on:` lines before it)
+    pub session: u32,
+    /// The root the package was emerged to
+    pub target_root: String,
+    /// The repository of the ebuild (empty if unknown)
+    pub repository: String,
+    /// The slot of the package (empty if unknown)
+    pub slot: String,
+}
+
+impl Merge {
+    /// Return the key of the history of the package, see [`history_key`]
+    pub fn key(&self) -> String {
+        history_key(&self.cpn, &self.repository, &self.target_root)
+    }
+
+    /// Return true if the merge is about `package` (see [`cpn_is_about`])
+    pub fn is_about(&self, package: &str) -> bool {
+        cpn_is_about(&self.cpn, package)
+    }
+}
+
+/// A completed unmerge, as read from the log
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unmerge {
+    /// The time the unmerge was started
+    pub time: u32,
+    /// The category/package_name representation of the package
+    pub cpn: String,
+    /// The version of the package (with the revision)
+    pub version: String,
+    /// The time it took to unmerge the package
+    pub duration: u32,
+    /// Was the package removed by emerge after the merge of another version (`AUTOCLEAN`) ?
+    pub autoclean: bool,
+    /// The emerge session the unmerge belongs to
+    pub session: u32,
 }
 
 /// Store the information about a emerged atom
+#[derive(Clone)]
 pub struct Atom {
     /// the category/package-name representation
     pub cpn: String,
@@ -60,6 +217,18 @@ pub struct Atom {
     pub worst_time: u32,
     /// the last time an emerge was started (avoid using PackageInfo)
     pub last_time: u32,
+    /// the full name and the time of each emerge, when read from a log
+    pub versions: Vec<(String, u32)>,
+    /// the repository of the ebuild (empty if unknown)
+    pub repository: String,
+    /// the root the package was emerged to
+    pub target_root: String,
+    /// the slot of the versions in `versions`, by full name, when known
+    pub slots: HashMap<String, String>,
+    /// the highest memory used by the last build recorded by `gls watch`, in kB
+    pub peak_rss: Option<u64>,
+    /// the size of the build log of the last emerge, in bytes (need split-log in FEATURES)
+    pub log_size: Option<u64>,
 }
 
 impl Atom {
@@ -76,7 +245,135 @@ impl Atom {
             best_time: time,
             worst_time: time,
             last_time,
+            versions: vec![],
+            repository: String::new(),
+            target_root: "/".to_string(),
+            slots: HashMap::new(),
+            peak_rss: None,
+            log_size: None,
+        }
+    }
+
+    /// Return the key of the history of the atom, see [`history_key`]
+    pub fn key(&self) -> String {
+        history_key(&self.cpn, &self.repository, &self.target_root)
+    }
+
+    /// Return the version of the last merge of the atom, if it was read from a log
+    pub fn last_version(&self) -> Option<&str> {
+        let (full_name, _) = self.versions.last()?;
+        split_cpv(full_name).map(|(_, version)| version)
+    }
+
+    /// Return the slot of `version` of the atom, if it can be found
+    ///
+    /// If the slot of this version is unknown, use the one of the versions sharing the most leading components with it
+    /// (`18.1.8` is in the slot of `18.1.7`, not in the one of `15.0.7`). Return `None` if these versions disagree.
+    pub fn slot_of(&self, version: &str) -> Option<&str> {
+        if let Some(slot) = self.slots.get(&format!("{}-{version}", self.cpn)) {
+            return Some(slot);
+        }
+
+        let wanted: Vec<&str> = version.split('.').collect();
+        let mut best = 0;
+        let mut found: Option<&str> = None;
+        for (full_name, slot) in &self.slots {
+            let known = full_name.get(self.cpn.len() + 1..).unwrap_or("");
+            let common = known
+                .split('.')
+                .zip(&wanted)
+                .take_while(|(a, b)| a == *b)
+                .count();
+            if (common == 0) || (common < best) {
+                continue;
+            }
+            if common > best {
+                best = common;
+                found = Some(slot);
+            } else if found != Some(slot.as_str()) {
+                found = None;
+            }
         }
+        found
+    }
+
+    /// Return the atom with only the emerges of the versions in `slot` (see [`Atom::slot_of`])
+    ///
+    /// Return `None` if no version is known to be in `slot`.
+    pub fn for_slot(&self, slot: &str) -> Option<Atom> {
+        let versions: Vec<(String, u32)> = self
+            .versions
+            .iter()
+            .filter(|(full_name, _)| {
+                let version = full_name.get(self.cpn.len() + 1..).unwrap_or("");
+                self.slot_of(version) == Some(slot)
+            })
+            .cloned()
+            .collect();
+        let ((_, first), rest) = versions.split_first()?;
+
+        let mut atom = Atom::new(self.cpn.clone(), *first, self.last_time);
+        for (_, time) in rest {
+            atom.add(*time);
+        }
+        atom.versions = versions;
+        atom.repository = self.repository.clone();
+        atom.target_root = self.target_root.clone();
+        atom.slots = self.slots.clone();
+        Some(atom)
+    }
+
+    /// Return the slots of the atom, sorted
+    pub fn known_slots(&self) -> Vec<&str> {
+        let mut slots: Vec<&str> = self.slots.values().map(String::as_str).collect();
+        slots.sort();
+        slots.dedup();
+        slots
+    }
+
+    /// Return true if the atom is about `package` (see [`cpn_is_about`])
+    pub fn is_about(&self, package: &str) -> bool {
+        cpn_is_about(&self.cpn, package)
+    }
+
+    /// Return a line with the statistics of the atom, for `gls stats`
+    ///
+    /// The peak memory and the size of the build log of the last emerge are added when known.
+    ///
+    /// # Examples
+    /// `app-misc/foo::guru  3 emerges, average 2m, best 1m, worst 3m`  
+    /// `www-client/chromium  2 emerges, average 9h, best 8h, worst 10h, peak 14.0 GB, build log 52 MB`
+    pub fn stats_line(&self) -> String {
+        let mut line = format!(
+            "{}  {} emerge{}, average {}, best {}, worst {}",
+            self.key(),
+            self.num_emerge,
+            if self.num_emerge > 1 { "s" } else { "" },
+            format_duration(self.time_avg() as u32),
+            format_duration(self.best_time),
+            format_duration(self.worst_time)
+        );
+        if let Some(peak) = self.peak_rss {
+            line.push_str(&format!(", peak {}", format_memory(peak)));
+        }
+        if let Some(size) = self.log_size {
+            line.push_str(&format!(", build log {}", format_memory(size / 1024)));
+        }
+        line
+    }
+
+    /// Return a warning if the last build recorded needed more than half of `total`, the memory of the machine in kB
+    ///
+    /// # Examples
+    /// `www-client/chromium needed 14.0 GB last time, this machine has 15.5 GB`
+    pub fn memory_warning(&self, total: u64) -> Option<String> {
+        let peak = self.peak_rss.filter(|peak| peak * 2 > total)?;
+        Some(format!(
+            "{} needed {} last time, this machine has {}",
+            self.key(),
+            format_memory(peak),
+            format_memory(total)
+        ))
     }
 
     /// Add an emerge time to the package
@@ -89,11 +386,52 @@ impl Atom {
         self.best_time = std::cmp::min(self.best_time, time);
     }
 
+    /// Return a copy of the atom with all the times multiplied by `factor`
+    ///
+    /// * `factor`: How much time the local machine needs compared to the one that created this atom
+    pub fn scaled(&self, factor: f64) -> Self {
+        let scale = |time: u32| (time as f64 * factor) as u32;
+        Self {
+            cpn: self.cpn.clone(),
+            num_emerge: self.num_emerge,
+            total_time: scale(self.total_time),
+            best_time: scale(self.best_time),
+            worst_time: scale(self.worst_time),
+            last_time: self.last_time,
+            versions: self
+                .versions
+                .iter()
+                .map(|(full_name, time)| (full_name.clone(), scale(*time)))
+                .collect(),
+            repository: self.repository.clone(),
+            target_root: self.target_root.clone(),
+            slots: self.slots.clone(),
+            peak_rss: self.peak_rss,
+            log_size: self.log_size,
+        }
+    }
+
+    /// Add all the emerges of `other` to this atom
+    pub fn merge(&mut self, other: &Atom) {
+        self.num_emerge += other.num_emerge;
+        self.total_time += other.total_time;
+        self.worst_time = std::cmp::max(self.worst_time, other.worst_time);
+        self.best_time = std::cmp::min(self.best_time, other.best_time);
+        self.versions.extend(other.versions.iter().cloned());
+        self.peak_rss = self.peak_rss.or(other.peak_rss);
+        self.log_size = self.log_size.or(other.log_size);
+        for (full_name, slot) in &other.slots {
+            self.slots
+                .entry(full_name.clone())
+                .or_insert_with(|| slot.clone());
+        }
+    }
+
     /// Compute the average time with filter
     ///
     /// This function return the average time for an emerge.  
     /// However, if there was more than 2 emerge done, then we do not take into account the worst and the best time
-    fn filter_time(&self) -> f64 {
+    pub fn filter_time(&self) -> f64 {
         let mut t = self.total_time;
         let mut n = self.num_emerge;
         if n > 2 {
@@ -105,7 +443,7 @@ impl Atom {
     }
 
     /// Return the average time for an emerge
-    fn time_avg(&self) -> f64 {
+    pub fn time_avg(&self) -> f64 {
         (self.total_time / self.num_emerge) as f64
     }
 
@@ -122,13 +460,15 @@ impl Atom {
     ///
     /// In the first 2 cases, we add 25% and a minute to the time[^note].
     /// [^note]: Yes, this means that the time after this can be worse than the worst time.
-    pub fn comp_avg(&self, over: &mut Over) -> f64 {
+    ///
+    /// * `clock`: Gives the current time, to know since when the emerge (started at [`Atom::last_time`]) is running
+    pub fn comp_avg(&self, clock: &dyn Clock, over: &mut Over) -> f64 {
         // time between the start of the emerge and now
-        let now = current_time() as u32;
+        let now = clock.now();
 
         let mut diff: f64 = 0.;
         if self.last_time != 0 {
-            diff = (now - self.last_time) as f64;
+            diff = now.saturating_sub(self.last_time) as f64;
         }
 
         // Compute the time diff between the average and now
@@ -183,11 +523,45 @@ impl Atom {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::FixedClock;
+
+    /// The current time during the tests
+    const NOW: u32 = 1234567890;
 
     fn setup_atom(time: u32) -> Atom {
         Atom::new("cpn".to_string(), time, 0)
     }
 
+    #[test]
+    fn cpn_about() {
+        assert!(cpn_is_about("sys-devel/gcc", "sys-devel/gcc"));
+        assert!(cpn_is_about("sys-devel/gcc", "gcc"));
+        assert!(!cpn_is_about("sys-devel/gcc", "sys-devel"));
+        assert!(!cpn_is_about("sys-devel/gcc", "devel/gcc"));
+    }
+
+    #[test]
+    fn atom_stats_metrics() {
+        let mut atom = Atom::new("www-client/chromium".to_string(), 60, 0);
+        assert_eq!(
+            atom.stats_line(),
+            "www-client/chromium  1 emerge, average 1m, best 1m, worst 1m"
+        );
+        assert_eq!(atom.memory_warning(16 * 1024 * 1024), None);
+
+        atom.peak_rss = Some(14 * 1024 * 1024);
+        atom.log_size = Some(52 * 1024 * 1024);
+        assert_eq!(
+            atom.stats_line(),
+            "www-client/chromium  1 emerge, average 1m, best 1m, worst 1m, peak 14.0 GB, build log 52 MB"
+        );
+        assert_eq!(
+            atom.memory_warning(16 * 1024 * 1024).unwrap(),
+            "www-client/chromium needed 14.0 GB last time, this machine has 16.0 GB"
+        );
+        assert_eq!(atom.memory_warning(64 * 1024 * 1024), None);
+    }
+
     #[test]
     fn package_info_cpn() {
         let p = PackageInfo {
@@ -197,9 +571,78 @@ mod tests {
             time: 1,
             is_binary: false,
             num: "".to_string(),
+            target_root: "/usr/aarch64-linux-gnu/".to_string(),
+            repository: "gentoo".to_string(),
+            slot: String::new(),
         };
 
         assert_eq!(p.cpn(), "a/b");
+        assert_eq!(p.version(), "0.0.1");
+        assert_eq!(p.key(), "a/b to /usr/aarch64-linux-gnu/");
+    }
+
+    #[test]
+    fn merge_kind_classify() {
+        assert_eq!(MergeKind::classify(None, "1.0"), MergeKind::New);
+        assert_eq!(
+            MergeKind::classify(Some("1.0"), "1.0_p1"),
+            MergeKind::Upgrade("1.0".to_string())
+        );
+        assert_eq!(
+            MergeKind::classify(Some("1.10"), "1.9"),
+            MergeKind::Downgrade("1.10".to_string())
+        );
+        assert_eq!(
+            MergeKind::classify(Some("1.0-r0"), "1.0"),
+            MergeKind::Rebuild
+        );
+        assert_eq!(
+            MergeKind::classify(Some("9999"), "9999"),
+            MergeKind::Rebuild
+        );
+        assert_eq!(MergeKind::Rebuild.describe(), "rebuild");
+    }
+
+    #[test]
+    fn atom_slots() {
+        let mut atom = Atom::new("a/b".to_string(), 100, 0);
+        atom.versions.push(("a/b-15.0.7".to_string(), 100));
+        for (version, time, slot) in [("18.1.6", 300, "18"), ("18.1.7", 500, "")] {
+            let full_name = format!("a/b-{version}");
+            atom.add(time);
+            if !slot.is_empty() {
+                atom.slots.insert(full_name.clone(), slot.to_string());
+            }
+            atom.versions.push((full_name, time));
+        }
+        atom.slots
+            .insert("a/b-15.0.7".to_string(), "15".to_string());
+
+        assert_eq!(atom.slot_of("18.1.8"), Some("18"));
+        assert_eq!(atom.slot_of("15.0.6"), Some("15"));
+        assert_eq!(atom.slot_of("19.0.0"), None);
+        let slot_18 = atom.for_slot("18").unwrap();
+        assert_eq!(slot_18.num_emerge, 2);
+        assert_eq!(slot_18.total_time, 800);
+        assert!(atom.for_slot("19").is_none());
+        assert_eq!(atom.known_slots(), vec!["15", "18"]);
+    }
+
+    #[test]
+    fn atom_last_version() {
+        let mut atom = setup_atom(1);
+        assert!(atom.last_version().is_none());
+        atom.versions.push(("a/b-1.2-r1".to_string(), 1));
+        assert_eq!(atom.last_version(), Some("1.2-r1"));
+    }
+
+    #[test]
+    fn history_key_native() {
+        assert_eq!(history_key("a/b", "gentoo", "/"), "a/b");
+        assert_eq!(history_key("a/b", "", "/"), "a/b");
+        assert_eq!(history_key("a/b", "guru", "/mnt/"), "a/b::guru to /mnt/");
+        assert_eq!(normalize_root("/mnt/stage"), "/mnt/stage/");
+        assert_eq!(normalize_root("/"), "/");
     }
 
     #[test]
@@ -230,6 +673,31 @@ mod tests {
         assert_eq!(p.total_time, 2 * time);
     }
 
+    #[test]
+    fn atom_scaled() {
+        let mut atom = setup_atom(10);
+        atom.add(30);
+        atom.versions.push(("a/b-1".to_string(), 30));
+        let atom = atom.scaled(1.5);
+        assert_eq!(atom.versions, vec![("a/b-1".to_string(), 45)]);
+        assert_eq!(atom.num_emerge, 2);
+        assert_eq!(atom.total_time, 60);
+        assert_eq!(atom.best_time, 15);
+        assert_eq!(atom.worst_time, 45);
+    }
+
+    #[test]
+    fn atom_merge() {
+        let mut atom = setup_atom(10);
+        let mut other = setup_atom(5);
+        other.add(40);
+        atom.merge(&other);
+        assert_eq!(atom.num_emerge, 3);
+        assert_eq!(atom.total_time, 55);
+        assert_eq!(atom.best_time, 5);
+        assert_eq!(atom.worst_time, 40);
+    }
+
     #[test]
     fn atom_filter_time_2() {
         let mut atom = setup_atom(10);
@@ -302,8 +770,8 @@ mod tests {
     fn atom_comp_avg_no_history() {
         let mut over = Over::NO;
         let mut atom = setup_atom(0);
-        atom.last_time = (current_time() - 1) as u32;
-        assert_eq!(atom.comp_avg(&mut over), 1.);
+        atom.last_time = NOW - 1;
+        assert_eq!(atom.comp_avg(&FixedClock(NOW), &mut over), 1.);
         assert!(matches!(over, Over::All));
     }
 
@@ -313,8 +781,11 @@ mod tests {
         // 52h 8m ago
         let time = 52 * 60 * 60 + 8 * 60;
         let mut atom = setup_atom(21);
-        atom.last_time = (current_time() - time) as u32;
-        assert_eq!(atom.comp_avg(&mut over), (time - 21) as f64);
+        atom.last_time = NOW - time;
+        assert_eq!(
+            atom.comp_avg(&FixedClock(NOW), &mut over),
+            (time - 21) as f64
+        );
         assert!(matches!(over, Over::All));
     }
 
@@ -324,8 +795,8 @@ mod tests {
         let mut atom = setup_atom(10);
         atom.add(10);
         atom.add(61);
-        atom.last_time = (current_time() - 15) as u32;
-        assert_eq!(atom.comp_avg(&mut over), 12. * 1.25 + 60.);
+        atom.last_time = NOW - 15;
+        assert_eq!(atom.comp_avg(&FixedClock(NOW), &mut over), 12. * 1.25 + 60.);
         assert!(matches!(over, Over::Avg));
     }
 
@@ -333,8 +804,8 @@ mod tests {
     fn atom_comp_avg_over_no() {
         let mut over = Over::NO;
         let mut atom = setup_atom(60);
-        atom.last_time = (current_time() - 10) as u32;
-        assert_eq!(atom.comp_avg(&mut over), 50. * 1.25 + 60.);
+        atom.last_time = NOW - 10;
+        assert_eq!(atom.comp_avg(&FixedClock(NOW), &mut over), 50. * 1.25 + 60.);
         assert!(matches!(over, Over::NO));
     }
 }
@@ -21,13 +21,177 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 // // // // // // // // // // // // // // // // // // // // // // // //
 
-use std::{collections::HashMap, error::Error, fs};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    error::Error,
+    fs, thread,
+};
 
 use crate::{
-    package::{Atom, PackageInfo},
-    useful::{get_size_cpn, LineType},
+    atom::{cpn_len, Cpv},
+    logfile::{
+        build_log_path, find_line_start, for_each_line, for_each_line_between, open_log,
+        plain_size, rfind_line,
+    },
+    metrics::read_peaks,
+    package::{normalize_root, Atom, Merge, PackageInfo, Unmerge},
+    resources::Resources,
+    session::{Session, SessionStatus},
+    slot::{read_vdb_slot, slot_from_args, split_slot_atom},
+    sync::{get_sync_repo, RepoSync},
+    useful::LineType,
 };
 
+/// Everything we learn while reading logs
+#[derive(Clone, Default)]
+pub struct LogState {
+    /// The emerges started but not completed yet, by full name
+    pub emerges_not_complete: HashMap<String, PackageInfo>,
+    /// The atoms of the completed emerges (binary excluded), by cpn
+    pub completed_atoms: HashMap<String, Atom>,
+    /// All the completed merges, in the order of the log
+    pub merges: Vec<Merge>,
+    /// The emerge sessions, in the order of the log
+    pub sessions: Vec<Session>,
+    /// The number of sessions started, kept when the ended sessions are removed from `sessions`
+    session_count: u32,
+    /// The synchronisations started but not completed yet: when they started, by repository
+    pub syncs_not_complete: HashMap<String, u32>,
+    /// All the ended synchronisations (completed or failed), in the order of the log
+    pub syncs: Vec<RepoSync>,
+    /// The unmerges started but not completed yet, by full name
+    pub unmerges_not_complete: HashMap<String, Unmerge>,
+    /// All the completed unmerges, in the order of the log
+    pub unmerges: Vec<Unmerge>,
+    /// The resources used by the running emerges, by full name (measured in /proc with `--resources`, see [`Liveness::measure`](crate::liveness::Liveness::measure))
+    pub resources: HashMap<String, Resources>,
+    /// The cpn of the package whose old versions are being removed ('>>> AUTOCLEAN: ...'), if any
+    autoclean: Option<String>,
+}
+
+impl LogState {
+    /// Return the id of the current session (0 if the log did not start a session yet)
+    pub fn session_id(&self) -> u32 {
+        self.session_count
+    }
+
+    /// Record a completed merge, and add its time to the atoms if it was not binary
+    pub fn add_merge(&mut self, merge: Merge) {
+        if !merge.is_binary {
+            let end = merge.time + merge.duration;
+            let atom = self
+                .completed_atoms
+                .entry(merge.key())
+                .and_modify(|atom| atom.add(merge.duration))
+                .or_insert_with(|| Atom {
+                    repository: merge.repository.clone(),
+                    target_root: merge.target_root.clone(),
+                    ..Atom::new(merge.cpn.clone(), merge.duration, end)
+                });
+            let full_name = format!("{}-{}", merge.cpn, merge.version);
+            if !merge.slot.is_empty() {
+                atom.slots.insert(full_name.clone(), merge.slot.clone());
+            }
+            atom.versions.push((full_name, merge.duration));
+        }
+        self.merges.push(merge);
+    }
+
+    /// Add what was learned from the part of a log that follows the one read in `self`
+    ///
+    /// `other` must start after a line '*** terminating.', so that nothing was running (see [`read_file_chunks`]).
+    /// Its lines before its first session belong to the last session of `self`.
+    pub fn append(&mut self, mut other: LogState) {
+        // The sessions of `other` were numbered from 1
+        let offset = self.session_id();
+        for session in &mut other.sessions {
+            session.id += offset;
+        }
+        for merge in &mut other.merges {
+            merge.session += offset;
+        }
+        for sync in &mut other.syncs {
+            sync.session += offset;
+        }
+        for unmerge in other
+            .unmerges
+            .iter_mut()
+            .chain(other.unmerges_not_complete.values_mut())
+        {
+            unmerge.session += offset;
+        }
+
+        for (key, atom) in other.completed_atoms {
+            match self.completed_atoms.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    let known = entry.get_mut();
+                    known.merge(&atom);
+                    // As with add_merge, the last slot read wins
+                    known.slots.extend(atom.slots);
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(atom);
+                }
+            }
+        }
+        self.merges.extend(other.merges);
+        self.sessions.extend(other.sessions);
+        self.session_count += other.session_count;
+        self.syncs.extend(other.syncs);
+        self.unmerges.extend(other.unmerges);
+        self.emerges_not_complete.extend(other.emerges_not_complete);
+        self.syncs_not_complete.extend(other.syncs_not_complete);
+        self.unmerges_not_complete
+            .extend(other.unmerges_not_complete);
+        self.autoclean = other.autoclean;
+    }
+
+    /// Add to the atoms the peak memory of their last build recorded in the file `metrics` by `gls watch`,
+    /// and the size of the build log of their last emerge in `build_log` (see [`build_log_path`])
+    pub fn read_metrics(&mut self, metrics: &str, build_log: &str) {
+        for (key, peak) in read_peaks(metrics) {
+            if let Some(atom) = self.completed_atoms.get_mut(&key) {
+                atom.peak_rss = Some(peak);
+            }
+        }
+
+        let mut last: HashMap<String, &Merge> = HashMap::new();
+        for merge in self.merges.iter().filter(|m| !m.is_binary) {
+            last.insert(merge.key(), merge);
+        }
+        for (key, merge) in last {
+            if let Some(atom) = self.completed_atoms.get_mut(&key) {
+                let full_name = format!("{}-{}", merge.cpn, merge.version);
+                atom.log_size = build_log_path(build_log, &full_name, merge.time)
+                    .and_then(|path| fs::metadata(path).ok())
+                    .map(|metadata| metadata.len());
+            }
+        }
+    }
+
+    /// Read the slots of the packages from the VDB, for the ones the logs did not give
+    ///
+    /// * `vdb`: The folder of the VDB (see [`Root::vdb`](crate::root::Root::vdb))
+    ///
+    /// Only the installed packages are in the VDB, so the other ones keep an unknown slot.
+    pub fn read_vdb_slots(&mut self, vdb: &str) {
+        for atom in self.completed_atoms.values_mut() {
+            for (full_name, _) in &atom.versions {
+                if !atom.slots.contains_key(full_name) {
+                    if let Some(slot) = read_vdb_slot(vdb, full_name) {
+                        atom.slots.insert(full_name.clone(), slot);
+                    }
+                }
+            }
+        }
+        for package in self.emerges_not_complete.values_mut() {
+            if package.slot.is_empty() {
+                package.slot = read_vdb_slot(vdb, &package.full_name).unwrap_or_default();
+            }
+        }
+    }
+}
+
 /// Build a [`PackageInfo`] struct with the information from `line`
 ///
 /// Use some invariance in the lines to create a [PackageInfo] instance.
@@ -52,7 +216,7 @@ fn build_package_info(
     let category = cpn[0..slash].to_string();
     let name = cpn[slash + 1..cpn.len()].to_string();
     let full_name = line[start_index..space].to_string();
-    let num = line[line.find('(')? + 1..line.find(')')?].to_string();
+    let num = line.get(line.find('(')? + 1..line.find(')')?)?.to_string();
     Some(PackageInfo {
         category,
         name,
@@ -60,21 +224,27 @@ fn build_package_info(
         time,
         is_binary,
         num,
+        target_root: "/".to_string(),
+        repository: String::new(),
+        slot: String::new(),
     })
 }
 
 /// The default function. Used for the starting emerge lines.  
 /// Use [`build_package_info`].
 pub fn get_info(line: &str) -> Option<PackageInfo> {
-    let time: u32 = line[0..line.find(':')?]
-        .parse()
-        .expect("Failed to convert the time to integer");
+    let time = get_line_time(line)?;
 
     // First character after the dash is a space
     let start_index = line.find(')').unwrap_or(line.len()) + 2;
-    let found = start_index + get_size_cpn(&line[start_index..])?;
+    let found = start_index + cpn_len(line.get(start_index..)?.split(' ').next()?);
 
-    build_package_info(line, start_index, found, time, false, ' ')
+    let mut info = build_package_info(line, start_index, found, time, false, ' ')?;
+    // The line ends with ' to /' or ' to /mnt/target/'
+    if let Some(pos) = line.rfind(" to ") {
+        info.target_root = normalize_root(line[pos + 4..].trim());
+    }
+    Some(info)
 }
 
 /// As the name suggest, used for lines that have 3 equals (merging lines).  
@@ -86,59 +256,243 @@ pub fn get_info(line: &str) -> Option<PackageInfo> {
 fn get_info_3equal(line: &str, position: usize) -> Option<PackageInfo> {
     let mut pos = position;
     if pos == 0 {
-        match line[24..].find(')') {
+        match line.get(24..)?.find(')') {
             Some(value) => pos = 24 + value,
             None => return None,
         }
     }
 
-    let time: u32 = line[0..line.find(':')?]
-        .parse()
-        .expect("Failed to convert the time to integer");
+    let time = get_line_time(line)?;
     // The smallest word is "Merging" (len 10: ') ' + 7 + ' '
     let index_after_merge = pos + 10;
 
     // If we can not find the values ('(', ':', [cpn]), we have to return
-    let start_index = index_after_merge + 1 + line[index_after_merge..].find('(')?;
+    let after_merge = line.get(index_after_merge..)?;
+    let start_index = index_after_merge + 1 + after_merge.find('(')?;
     let end_pos = start_index + line[start_index..].find(':')?;
-    let found = start_index + get_size_cpn(&line[start_index..end_pos])?;
+    let found = start_index + cpn_len(&line[start_index..end_pos]);
 
-    let is_binary = line[index_after_merge..].starts_with("B"); // ...) Merging Binary (xxx/yyy...)
+    let is_binary = after_merge.starts_with("B"); // ...) Merging Binary (xxx/yyy...)
 
     build_package_info(line, start_index, found, time, is_binary, ':')
 }
 
+/// Return the repository of the ebuild given in a line '=== (1 of 1) Cleaning (a/b-1.2.3::/var/db/repos/gentoo/a/b/b-1.2.3.ebuild)'
+///
+/// * `line`: The line, with the path to the ebuild after `::`
+/// * `category`: The category of the package, the repository is the folder that contains it
+///
+/// Return `None` if the path is not an ebuild (binary packages).
+fn get_repository(line: &str, category: &str) -> Option<String> {
+    let path = &line[line.find("::")? + 2..line.rfind(')')?];
+    if !path.ends_with(".ebuild") {
+        return None;
+    }
+    let folder = &path[..path.rfind(&format!("/{category}/"))?];
+    Some(folder[folder.rfind('/')? + 1..].to_string())
+}
+
 /// Complete an emerge.
 ///
 /// * `complete_line`: The complete (merge) line
-/// * `emerges_not_complete`: The HashMap that contains all emerges not yet completed.  
-///   The package from `complete_line` is removed from it
-/// * `completed_atoms`: The HashMap where we store the atoms. We add to it the package from `complete_line`
-fn complete_emerge(
-    line: &str,
-    emerges_not_complete: &mut HashMap<String, PackageInfo>,
-    completed_atoms: &mut HashMap<String, Atom>,
-) {
+/// * `state`: The package from `complete_line` is removed from [`LogState::emerges_not_complete`], and added to the merges (see [`LogState::add_merge`])
+fn complete_emerge(line: &str, state: &mut LogState) {
     let p = match get_info(line) {
         Some(info) => info,
         None => return,
     };
 
-    if let Some(m) = emerges_not_complete.get(&p.full_name) {
-        // compare the packages with the version
-        if (m.full_name == p.full_name) && !m.is_binary {
-            // Time will never be less than 0
-            let time = p.time - m.time;
-            match completed_atoms.get_mut(&m.cpn()) {
-                Some(atom) => atom.add(time),
-                None => {
-                    let a = Atom::new(m.cpn(), time, p.time);
-                    completed_atoms.insert(m.cpn(), a);
-                }
-            }
+    if let Some((_, m)) = state.emerges_not_complete.remove_entry(&p.full_name) {
+        // Time will never be less than 0
+        let merge = Merge {
+            time: m.time,
+            cpn: m.cpn(),
+            version: m.version(),
+            is_binary: m.is_binary,
+            duration: p.time.saturating_sub(m.time),
+            num: m.num,
+            session: state.session_id(),
+            target_root: m.target_root,
+            repository: m.repository,
+            slot: m.slot,
+        };
+        if let Some(session) = state.sessions.last_mut() {
+            session.merged.push(m.full_name);
+        }
+        state.add_merge(merge);
+    }
+}
+
+/// Return the time at which `line` was written
+pub fn get_line_time(line: &str) -> Option<u32> {
+    line[0..line.find(':')?].parse().ok()
+}
+
+/// Start a new session. The previous one is marked as interrupted if it did not end
+fn start_session(line: &str, state: &mut LogState) {
+    let time = match get_line_time(line) {
+        Some(t) => t,
+        None => return,
+    };
+    fail_syncs(state);
+    state.unmerges_not_complete.clear();
+    state.autoclean = None;
+    if let Some(previous) = state.sessions.last_mut() {
+        if previous.status == SessionStatus::Running {
+            previous.status = SessionStatus::Interrupted;
         }
-        emerges_not_complete.remove_entry(&p.full_name);
     }
+    state.session_count += 1;
+    state.sessions.push(Session::new(state.session_count, time));
+}
+
+/// Set the status of the current session from a line '*** exiting ...'
+fn exit_session(line: &str, state: &mut LogState) {
+    let Some(rest) = line.get(17..) else {
+        return;
+    };
+    if let Some(session) = state.sessions.last_mut() {
+        session.status = if rest.starts_with("exiting successfully") {
+            SessionStatus::Success
+        } else {
+            // '*** exiting unsuccessfully with status '1'.'
+            let code = rest.split('\'').nth(1).unwrap_or("?");
+            SessionStatus::Failure(code.to_string())
+        };
+    }
+}
+
+/// Start the synchronisation of a repository, from a line '>>> Syncing repository ...' (or older equivalents)
+///
+/// The lines 'Starting rsync' and 'Git pull' only start a synchronisation if none is running,
+/// as recent versions of portage write them after 'Syncing repository', with another name.
+fn start_sync(line: &str, state: &mut LogState) {
+    let Some(rest) = line.get(12..) else {
+        return;
+    };
+    if !rest.starts_with(">>> Syncing repository") && !state.syncs_not_complete.is_empty() {
+        return;
+    }
+    if let (Some(time), Some(repo)) = (get_line_time(line), get_sync_repo(rest)) {
+        state.syncs_not_complete.entry(repo).or_insert(time);
+    }
+}
+
+/// Complete the synchronisation of a repository, from a line '=== Sync completed for ...'
+fn complete_sync(line: &str, state: &mut LogState) {
+    let (Some(time), Some(rest)) = (get_line_time(line), line.get(12..)) else {
+        return;
+    };
+    let repo = rest.trim_start_matches("=== Sync completed for ").trim();
+    // Some logs name the repository differently at the start (the last part of the URI), so take the only one running
+    let key = if state.syncs_not_complete.contains_key(repo) || state.syncs_not_complete.len() != 1
+    {
+        repo.to_string()
+    } else {
+        state.syncs_not_complete.keys().next().unwrap().clone()
+    };
+    if let Some(start) = state.syncs_not_complete.remove(&key) {
+        state.syncs.push(RepoSync {
+            repo: repo.to_string(),
+            start,
+            duration: Some(time.saturating_sub(start)),
+            session: state.session_id(),
+        });
+    }
+}
+
+/// Start the unmerge of a package, from a line '=== Unmerging... (category/package-1.2.3)'
+///
+/// The unmerge is an autoclean if it follows a line '>>> AUTOCLEAN: category/package:slot' for the same package.
+fn start_unmerge(line: &str, state: &mut LogState) {
+    let Some(rest) = line.get(12..).map(str::trim_start) else {
+        return;
+    };
+    let full_name = match rest
+        .strip_prefix("=== Unmerging... (")
+        .and_then(|r| r.strip_suffix(')'))
+    {
+        Some(name) => name,
+        None => return,
+    };
+    let (time, cpv) = match (get_line_time(line), full_name.parse::<Cpv>()) {
+        (Some(t), Ok(cpv)) => (t, cpv),
+        _ => return,
+    };
+
+    let cpn = cpv.cpn();
+    let unmerge = Unmerge {
+        time,
+        autoclean: state.autoclean.as_ref() == Some(&cpn),
+        version: cpv.version.to_string(),
+        cpn,
+        duration: 0,
+        session: state.session_id(),
+    };
+    state
+        .unmerges_not_complete
+        .insert(full_name.to_string(), unmerge);
+}
+
+/// End the unmerge of a package, from a line '>>> unmerge success: ...' or '!!! unmerge FAILURE: ...'
+///
+/// Only the successful unmerges are recorded.
+fn end_unmerge(line: &str, state: &mut LogState) {
+    let Some(rest) = line.get(12..).map(str::trim_start) else {
+        return;
+    };
+    let (full_name, success) = match rest.strip_prefix(">>> unmerge success: ") {
+        Some(name) => (name, true),
+        None => match rest.strip_prefix("!!! unmerge FAILURE: ") {
+            Some(name) => (name, false),
+            None => return,
+        },
+    };
+
+    if let Some(mut unmerge) = state.unmerges_not_complete.remove(full_name.trim()) {
+        if success {
+            unmerge.duration = get_line_time(line)
+                .unwrap_or(unmerge.time)
+                .saturating_sub(unmerge.time);
+            state.unmerges.push(unmerge);
+        }
+    }
+}
+
+/// Mark all the synchronisations not completed as failed
+fn fail_syncs(state: &mut LogState) {
+    let session = state.session_id();
+    let mut failed: Vec<RepoSync> = state
+        .syncs_not_complete
+        .drain()
+        .map(|(repo, start)| RepoSync {
+            repo,
+            start,
+            duration: None,
+            session,
+        })
+        .collect();
+    failed.sort_by_key(|s| s.start);
+    state.syncs.extend(failed);
+}
+
+/// End the current session: the emerges not completed are failed
+fn terminate_session(line: &str, state: &mut LogState) {
+    if let Some(session) = state.sessions.last_mut() {
+        if session.end.is_none() {
+            session.end = get_line_time(line);
+        }
+        if session.status == SessionStatus::Running {
+            session.status = SessionStatus::Terminated;
+        }
+        session
+            .failed
+            .extend(state.emerges_not_complete.keys().cloned());
+    }
+    state.emerges_not_complete.clear();
+    state.unmerges_not_complete.clear();
+    state.autoclean = None;
+    fail_syncs(state);
 }
 
 fn is_line_merging_binary(line: &str) -> bool {
@@ -167,8 +521,14 @@ fn select_line_type(line: &str) -> LineType {
     // them, as we have until the end of 2286 before we have to use 11
     // characters for the date, and we use 10 characters since 2001.
 
-    // All lines of interest, are different in position 14
-    let interesting = &line[13..18];
+    // All lines of interest, are different in position 14.
+    // A line too short, or with invalid bytes (replaced by U+FFFD) there, is not one of them
+    let (Some(interesting), Some(rest)) = (line.get(13..18), line.get(12..)) else {
+        return LineType::Unknow;
+    };
+    if !interesting.is_ascii() {
+        return LineType::Unknow;
+    }
 
     if interesting.starts_with(">") && interesting.ends_with("e") {
         // Catch all '%d: >>> emerge %s'
@@ -178,22 +538,42 @@ fn select_line_type(line: &str) -> LineType {
         if is_line_merging_binary(line) {
             return LineType::MergeBinary;
         }
+        return LineType::Build;
     } else if interesting.starts_with(":") && interesting.ends_with("c") {
         // End of a completed merge
         return LineType::End;
     } else if interesting.starts_with("*") && interesting.ends_with("t") {
         // Line of format '%d:  *** terminating.'
         return LineType::Term;
+    } else if interesting.starts_with("*") && interesting.ends_with("e") {
+        // Either '%d:  *** emerge %s' or '%d:  *** exiting %s'
+        // '*** ' ends at 17, inside `interesting`
+        if rest[5..].starts_with("emerge ") {
+            return LineType::Command;
+        } else if rest[5..].starts_with("exiting ") {
+            return LineType::Exit;
+        }
+    } else if rest.starts_with("Started emerge on:") {
+        return LineType::Session;
+    } else if rest.trim_start().starts_with(">>> AUTOCLEAN: ") {
+        return LineType::Autoclean;
+    } else if rest.trim_start().starts_with("=== Unmerging... (") {
+        return LineType::UnmergeStart;
+    } else if rest.trim_start().starts_with(">>> unmerge success: ")
+        || rest.trim_start().starts_with("!!! unmerge FAILURE: ")
+    {
+        return LineType::UnmergeEnd;
+    } else if rest.starts_with("=== Sync completed for ") {
+        return LineType::SyncEnd;
+    } else if get_sync_repo(rest).is_some() {
+        // '%d: >>> Syncing repository ...', '%d: >>> Starting rsync with ...' or '%d: >>> Git pull in ...'
+        return LineType::SyncStart;
     }
     LineType::Unknow
 }
 
 /// Select an action based on the line type
-fn act_on_line(
-    line: &str,
-    emerges_not_complete: &mut HashMap<String, PackageInfo>,
-    completed_atoms: &mut HashMap<String, Atom>,
-) {
+pub fn act_on_line(line: &str, state: &mut LogState) {
     // skip empty line or those starting with # (for testing purpose)
     if line.is_empty() || line.starts_with("#") {
         return;
@@ -208,45 +588,230 @@ fn act_on_line(
             } else {
                 get_info_3equal(line, 0)
             } {
-                emerges_not_complete.insert(info.full_name.clone(), info);
+                // A binary merge keeps the start of its emerge, so that its duration is correct
+                match state.emerges_not_complete.get_mut(&info.full_name) {
+                    Some(p) if info.is_binary => p.is_binary = true,
+                    _ => {
+                        let mut info = info;
+                        if let Some(session) = state.sessions.last_mut() {
+                            session.attempted.push(info.full_name.clone());
+                            // The slot can be given in the arguments: 'emerge sys-devel/llvm:18'
+                            let slot = slot_from_args(&session.args, &info.cpn());
+                            info.slot = slot.unwrap_or_default();
+                        }
+                        state
+                            .emerges_not_complete
+                            .insert(info.full_name.clone(), info);
+                    }
+                }
+            }
+        }
+        LineType::Build => {
+            // The first step of the build gives the path of the ebuild, and so its repository
+            if let Some(info) = get_info_3equal(line, 0) {
+                if let Some(p) = state.emerges_not_complete.get_mut(&info.full_name) {
+                    if p.repository.is_empty() {
+                        p.repository = get_repository(line, &p.category).unwrap_or_default();
+                    }
+                }
+            }
+        }
+        LineType::End => complete_emerge(line, state),
+        LineType::Term => terminate_session(line, state),
+        LineType::Session => start_session(line, state),
+        LineType::Command => {
+            // Skip '*** emerge '
+            if let (Some(session), Some(args)) = (state.sessions.last_mut(), line.get(24..)) {
+                session.args = args.to_string();
             }
         }
-        LineType::End => complete_emerge(line, emerges_not_complete, completed_atoms),
-        LineType::Term => {
-            emerges_not_complete.clear();
+        LineType::Exit => exit_session(line, state),
+        LineType::SyncStart => start_sync(line, state),
+        LineType::SyncEnd => complete_sync(line, state),
+        LineType::Autoclean => {
+            // '>>> AUTOCLEAN: category/package:slot'
+            let Some(rest) = line.get(12..) else {
+                return;
+            };
+            let atom = rest.trim_start().trim_start_matches(">>> AUTOCLEAN: ");
+            let cpn = atom.split(':').next().unwrap_or(atom);
+            state.autoclean = Some(cpn.to_string());
+            // The old versions removed are in the slot of the package just merged
+            if let Some((_, slot)) = split_slot_atom(atom) {
+                for p in state.emerges_not_complete.values_mut() {
+                    if (p.cpn() == cpn) && p.slot.is_empty() {
+                        p.slot = slot.to_string();
+                    }
+                }
+            }
         }
+        LineType::UnmergeStart => start_unmerge(line, state),
+        LineType::UnmergeEnd => end_unmerge(line, state),
         LineType::Unknow => (),
     }
 }
 
-/// Read the whole file given and update `emerges_not_complete` and `completed_atoms` as we go.
+/// The size of the part of a log read by each thread
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Read the whole file given and update `state` as we go.
+///
+/// * `file`: The path as string to the file we want to read, it can be compressed (see [`open_log`])
+/// * `state`: What we learned from the previous files. Use [`LogState::default`] for the first one
+///
+/// The big logs that are not compressed are read in parallel (see [`read_file_chunks`]).
+pub fn read_file(file: &str, state: &mut LogState) -> Result<(), Box<dyn Error>> {
+    if let Some(size) = plain_size(file) {
+        return read_plain(file, size, state);
+    }
+
+    for_each_line(open_log(file)?, |line| act_on_line(line, state))?;
+
+    Ok(())
+}
+
+/// Read the first `size` bytes of the plain log `file` (`size` must be the start of a line, or the end of the file)
+///
+/// The big logs are read in parallel (see [`read_file_chunks`]).
+pub fn read_plain(file: &str, size: u64, state: &mut LogState) -> Result<(), Box<dyn Error>> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunks = std::cmp::min(threads as u64, size / CHUNK_SIZE);
+    if chunks > 1 {
+        return read_file_chunks(file, size, chunks, state);
+    }
+    for_each_line_between(file, 0, size, |line| act_on_line(line, state))?;
+    Ok(())
+}
+
+/// Return true if `line` is '*** terminating.': everything running is stopped
+fn is_terminating(line: &str) -> bool {
+    line.get(12..)
+        .is_some_and(|r| r.starts_with(" *** terminating."))
+}
+
+/// Return true if `line` is 'Started emerge on:'
+fn is_session_start(line: &str) -> bool {
+    line.get(11..)
+        .is_some_and(|r| r.starts_with(" Started emerge on:"))
+}
+
+/// Return true if the state can be split before `line`: all the emerges were stopped by `previous`, and `line` starts a new session
+fn is_chunk_start(previous: &str, line: &str) -> bool {
+    is_terminating(previous) && is_session_start(line)
+}
+
+/// Read the end of the plain log `file`, from the last line '*** terminating.', and return what is running
 ///
-/// * `file`: The path as string to the file we want to read
-/// * `emerges_not_complete`: The HashMap that contains all emerges not yet completed
-/// * `completed_atoms`: The HashMap where we store the atoms.
-pub fn read_file(
+/// As nothing is running after this line, the packages being emerged, synchronised or unmerged are all in this part.
+/// Return the position of the start of this part with what was learned from it,
+/// or `None` if the log is compressed or was never terminated.
+pub fn read_tail(file: &str) -> Result<Option<(u64, LogState)>, Box<dyn Error>> {
+    let Some(size) = plain_size(file) else {
+        return Ok(None);
+    };
+    let Some(start) = rfind_line(file, is_terminating)? else {
+        return Ok(None);
+    };
+
+    let mut state = LogState::default();
+    for_each_line_between(file, start, size, |line| act_on_line(line, &mut state))?;
+    Ok(Some((start, state)))
+}
+
+/// Return true if `line` can change the history of the packages in `needles` (category/name),
+/// or the one of the synchronisations if `syncs`
+fn is_line_about(line: &str, needles: &[String], syncs: bool) -> bool {
+    if needles.iter().any(|n| line.contains(n.as_str()))
+        || is_terminating(line)
+        || is_session_start(line)
+    {
+        return true;
+    }
+    syncs
+        && line.get(13..18).is_some()
+        && matches!(
+            select_line_type(line),
+            LineType::SyncStart | LineType::SyncEnd
+        )
+}
+
+/// Read `file` as [`read_file`], but only act on the lines that are about the packages in `needles`
+/// (and on the synchronisations if `syncs`). Stop at the byte `end` if given (the log must not be compressed).
+///
+/// The other lines are skipped without being parsed, so this is much faster when only a few packages are wanted.
+pub fn read_file_about(
     file: &str,
-    emerges_not_complete: &mut HashMap<String, PackageInfo>,
-    completed_atoms: &mut HashMap<String, Atom>,
+    end: Option<u64>,
+    needles: &[String],
+    syncs: bool,
+    state: &mut LogState,
 ) -> Result<(), Box<dyn Error>> {
-    let content = fs::read_to_string(file)?;
-
-    for line in content.lines() {
-        act_on_line(line, emerges_not_complete, completed_atoms);
+    let action = |line: &str| {
+        if is_line_about(line, needles, syncs) {
+            act_on_line(line, state);
+        }
+    };
+    match end {
+        Some(end) => for_each_line_between(file, 0, end, action)?,
+        None => for_each_line(open_log(file)?, action)?,
     }
 
     Ok(())
 }
 
+/// Read the plain log `file` of `size` bytes in `chunks` parts at the same time, and update `state`
+///
+/// The log is split before the sessions following a line '*** terminating.', as nothing is running there:
+/// each part can be read from an empty [`LogState`], and the results are appended in order (see [`LogState::append`]).
+/// The first part is read on `state` directly, as it can continue the emerges of the previous file.
+fn read_file_chunks(
+    file: &str,
+    size: u64,
+    chunks: u64,
+    state: &mut LogState,
+) -> Result<(), Box<dyn Error>> {
+    let mut starts = vec![0];
+    for n in 1..chunks {
+        let from = std::cmp::max(size * n / chunks, *starts.last().unwrap());
+        match find_line_start(file, from, is_chunk_start)? {
+            Some(start) => starts.push(start),
+            None => break,
+        }
+    }
+    starts.dedup();
+    starts.push(size);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = starts[1..]
+            .windows(2)
+            .map(|range| {
+                scope.spawn(move || {
+                    let mut part = LogState::default();
+                    for_each_line_between(file, range[0], range[1], |line| {
+                        act_on_line(line, &mut part)
+                    })
+                    .map(|_| part)
+                })
+            })
+            .collect();
+
+        for_each_line_between(file, starts[0], starts[1], |line| act_on_line(line, state))?;
+        for worker in workers {
+            let part = worker.join().expect("A thread reading the log panicked")?;
+            state.append(part);
+        }
+        Ok(())
+    })
+}
+
 #[cfg(test)]
-/// Read the file given in argument, and output the HashMap of the emerges not complete, and the one of completed atoms
-pub fn read_file_test(file: &str) -> (HashMap<String, PackageInfo>, HashMap<String, Atom>) {
-    let mut emerges_not_complete: HashMap<String, PackageInfo> = HashMap::new();
-    let mut completed_atoms: HashMap<String, Atom> = HashMap::new();
-    let result = read_file(file, &mut emerges_not_complete, &mut completed_atoms);
+/// Read the file given in argument, and output what we learned from it
+pub fn read_file_test(file: &str) -> LogState {
+    let mut state = LogState::default();
+    let result = read_file(file, &mut state);
     assert!(result.is_ok());
 
-    (emerges_not_complete, completed_atoms)
+    state
 }
 
 #[cfg(test)]
@@ -260,17 +825,45 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn get_info_no_time() {
-        let line = "aaaaa:";
-        get_info(line);
+        assert!(get_info("aaaaa:").is_none());
+        assert!(get_info("aaaaaaaaaa:  >>> emerge (1 of 1) a/b-1 to /").is_none());
+        // The invalid bytes of the time are replaced by U+FFFD
+        let line = String::from_utf8_lossy(b"12345678\xff:  >>> emerge (1 of 1) a/b-1 to /");
+        assert!(get_info(&line).is_none());
+        let line = "aaaaaaaaaa:  === (1 of 1) Merging Binary (a/b-1::/x.ebuild)";
+        assert!(get_info_3equal(line, 0).is_none());
     }
 
     #[test]
-    #[should_panic]
     fn get_info_without_cpn() {
-        let line = "146181: ";
-        get_info(line);
+        assert!(get_info("146181: ").is_none());
+    }
+
+    #[test]
+    fn complete_before_start() {
+        let mut state = LogState::default();
+        act_on_line("1234567890:  >>> emerge (1 of 1) a/b-1 to /", &mut state);
+        // A corrupted time
+        act_on_line(
+            "0234567890:  ::: completed emerge (1 of 1) a/b-1 to /",
+            &mut state,
+        );
+        assert_eq!(state.merges[0].duration, 0);
+    }
+
+    #[test]
+    fn get_info_truncated() {
+        let mut state = LogState::default();
+        for line in [
+            "1234567890:  >>> emer",
+            "1234567890:  >>> emerge (1 of 1)",
+            "1234567890:  >>> emerge ) a/b-1 (",
+            "1234567890:  === (1 of 1) Merging Binary (a/b-1",
+        ] {
+            act_on_line(line, &mut state);
+        }
+        assert!(state.emerges_not_complete.is_empty());
     }
 
     #[test]
@@ -288,6 +881,27 @@ mod tests {
         assert_eq!(p.time, 146181);
     }
 
+    #[test]
+    fn get_info_target_root() {
+        let line = "146181:  >>> emerge (1 of 1) a/b-0 to /usr/aarch64-linux-gnu/";
+        let p = get_info(line).unwrap();
+        assert_eq!(p.full_name, "a/b-0");
+        assert_eq!(p.target_root, "/usr/aarch64-linux-gnu/");
+        assert_eq!(p.key(), "a/b to /usr/aarch64-linux-gnu/");
+    }
+
+    #[test]
+    fn read_file_target_root() {
+        let state = read_file_test("./tests/emerge.log/cross");
+
+        assert_eq!(state.completed_atoms.len(), 2);
+        assert_eq!(state.completed_atoms["category/package"].total_time, 100);
+        let cross = &state.completed_atoms["category/package to /usr/aarch64-linux-gnu/"];
+        assert_eq!(cross.cpn, "category/package");
+        assert_eq!(cross.total_time, 20);
+        assert_eq!(state.merges[1].target_root, "/usr/aarch64-linux-gnu/");
+    }
+
     #[test]
     fn get_info_3equal_with_cpn() {
         let line = "1234567890:  === (1 of 1) Merging (app/testing-1.2.3::/var/db/repos/gentoo/app/testing/testing-1.2.3.ebuild)";
@@ -315,6 +929,26 @@ mod tests {
         get_info_3equal(line, 0).unwrap();
     }
 
+    #[test]
+    fn repository_from_line() {
+        let line = "1234567890:  === (1 of 1) Cleaning (app/testing-1.2.3::/var/db/repos/guru/app/testing/testing-1.2.3.ebuild)";
+        assert_eq!(get_repository(line, "app").unwrap(), "guru");
+        let line = "1234567890:  === (1 of 1) Merging Binary (app/testing-1.2.3::/var/cache/binpkgs/app/testing/testing-1.2.3.gpkg.tar)";
+        assert!(get_repository(line, "app").is_none());
+    }
+
+    #[test]
+    fn read_file_repository() {
+        let state = read_file_test("./tests/emerge.log/repository");
+
+        assert_eq!(state.completed_atoms.len(), 2);
+        assert_eq!(state.completed_atoms["category/package"].total_time, 100);
+        let overlay = &state.completed_atoms["category/package::guru"];
+        assert_eq!(overlay.repository, "guru");
+        assert_eq!(overlay.total_time, 20);
+        assert_eq!(state.merges[0].repository, "gentoo");
+    }
+
     #[test]
     fn line_is_start() {
         let line = "1234567890:  >>> emerge (1 of 1) sys-devel/gcc-1.2.3 to /";
@@ -334,11 +968,104 @@ mod tests {
     }
 
     #[test]
-    fn line_is_unknow() {
+    fn line_is_session() {
+        let line = "1234567890: Started emerge on: Sep 30, 2024 14:00:03";
+        assert!(std::matches!(select_line_type(line), LineType::Session));
+        let line = "1234567890:  *** emerge --update @world";
+        assert!(std::matches!(select_line_type(line), LineType::Command));
+        let line = "1234567890:  *** exiting successfully.";
+        assert!(std::matches!(select_line_type(line), LineType::Exit));
+    }
+
+    #[test]
+    fn line_is_sync() {
+        let line = "1234567890: >>> Syncing repository 'gentoo' into '/var/db/repos/gentoo'...";
+        assert!(std::matches!(select_line_type(line), LineType::SyncStart));
+        let line = "1234567890: >>> Starting rsync with rsync://rsync.gentoo.org/gentoo-portage";
+        assert!(std::matches!(select_line_type(line), LineType::SyncStart));
+        let line = "1234567890: === Sync completed for gentoo";
+        assert!(std::matches!(select_line_type(line), LineType::SyncEnd));
+    }
+
+    #[test]
+    fn line_is_unmerge() {
         let line = "1234567890:  >>> AUTOCLEAN: sec-policy/selinux-java:0";
+        assert!(std::matches!(select_line_type(line), LineType::Autoclean));
+        let line = "1234567890:  === Unmerging... (sec-policy/selinux-java-2.2)";
+        assert!(std::matches!(
+            select_line_type(line),
+            LineType::UnmergeStart
+        ));
+        let line = "1234567890:  >>> unmerge success: sec-policy/selinux-java-2.2";
+        assert!(std::matches!(select_line_type(line), LineType::UnmergeEnd));
+    }
+
+    #[test]
+    fn line_is_unknow() {
+        let line = "1234567890:  >>> Regenerating /etc/ld.so.cache...";
         assert!(std::matches!(select_line_type(line), LineType::Unknow));
     }
 
+    #[test]
+    fn line_is_invalid() {
+        // The invalid bytes are replaced by U+FFFD, that takes 3 bytes
+        let line = String::from_utf8_lossy(b"1234567890: \xff\xfe Started emerge on: x");
+        assert!(std::matches!(select_line_type(&line), LineType::Unknow));
+        let line = String::from_utf8_lossy(b"1234567890:  ==\xff (1 of 1) Merging Binary (a/b-1:)");
+        assert!(std::matches!(select_line_type(&line), LineType::Unknow));
+        let line = String::from_utf8_lossy(b"1234567890:  === (1 of 1) \xff (a/b-1:)");
+        let mut state = LogState::default();
+        act_on_line(&line, &mut state);
+        assert!(state.emerges_not_complete.is_empty());
+    }
+
+    #[test]
+    fn session_line_is_cut() {
+        let mut state = LogState::default();
+        act_on_line(
+            "1234567890: Started emerge on: Sep 30, 2024 14:00:03",
+            &mut state,
+        );
+        // The end of the line is not written yet
+        exit_session("1234567890:  ***", &mut state);
+        assert_eq!(state.sessions[0].status, SessionStatus::Running);
+    }
+
+    #[test]
+    fn sync_line_is_cut() {
+        let mut state = LogState::default();
+        start_sync("1234567890:", &mut state);
+        complete_sync("1234567890:", &mut state);
+        // The invalid bytes are replaced by U+FFFD, that takes 3 bytes
+        let line = String::from_utf8_lossy(b"1234567890:\xff>>> Syncing repository 'gentoo'");
+        start_sync(&line, &mut state);
+        complete_sync(&line, &mut state);
+        assert!(state.syncs_not_complete.is_empty() && state.syncs.is_empty());
+    }
+
+    #[test]
+    fn unmerge_line_is_cut() {
+        let mut state = LogState::default();
+        start_unmerge("1234567890:", &mut state);
+        end_unmerge("1234567890:", &mut state);
+        // The invalid bytes are replaced by U+FFFD, that takes 3 bytes
+        let line = String::from_utf8_lossy(b"1234567890:\xff=== Unmerging... (a/b-1.0)");
+        start_unmerge(&line, &mut state);
+        assert!(state.unmerges_not_complete.is_empty());
+    }
+
+    #[test]
+    fn line_is_short() {
+        let mut state = LogState::default();
+        for line in ["1234567890: ", "1234567890:  ***", "1234567890:  *** e"] {
+            assert!(std::matches!(select_line_type(line), LineType::Unknow));
+            act_on_line(line, &mut state);
+        }
+        // Long enough to be a step of a build, but too short to have the package
+        act_on_line("1234567890:  === (", &mut state);
+        assert!(state.sessions.is_empty());
+    }
+
     #[test]
     fn line_is_not_merging_binary() {
         let line = "1234567890:  === (9 of 15) Cleaning (a/b-1.2.3::...";
@@ -371,19 +1098,215 @@ mod tests {
 
     #[test]
     fn read_file_two_package_with_1binary() {
-        let (emerges_not_complete, completed_atoms) =
-            read_file_test("./tests/emerge.log/two_with_1binary");
+        let state = read_file_test("./tests/emerge.log/two_with_1binary");
+
+        assert_eq!(state.emerges_not_complete.len(), 0);
+        assert_eq!(state.completed_atoms.len(), 1); // Binary package are not added to it
+        let atom = state.completed_atoms.get("category/package").unwrap();
+        assert_eq!(
+            atom.versions,
+            vec![("category/package-1.2.3".to_string(), 11)]
+        );
+    }
+
+    #[test]
+    fn read_file_sessions() {
+        let state = read_file_test("./tests/emerge.log/sessions");
+
+        assert_eq!(state.sessions.len(), 3);
+        let first = &state.sessions[0];
+        assert_eq!(first.args, "--oneshot category/package");
+        assert_eq!(first.start, 1234567800);
+        assert_eq!(first.end, Some(1234567812));
+        assert_eq!(first.status, SessionStatus::Success);
+        assert_eq!(first.merged, vec!["category/package-1.2.3"]);
+
+        let second = &state.sessions[1];
+        assert_eq!(second.status, SessionStatus::Failure("1".to_string()));
+        assert_eq!(second.attempted.len(), 2);
+        assert_eq!(second.merged, vec!["category/package-1.2.3"]);
+        assert_eq!(second.failed, vec!["category/other-2.0"]);
+
+        let third = &state.sessions[2];
+        assert_eq!(third.status, SessionStatus::Running);
+        assert_eq!(third.end, None);
+        assert_eq!(state.emerges_not_complete.len(), 1);
+    }
+
+    #[test]
+    fn read_file_syncs() {
+        let state = read_file_test("./tests/emerge.log/sync");
+
+        assert_eq!(
+            state.syncs,
+            vec![
+                RepoSync {
+                    repo: "gentoo".to_string(),
+                    start: 1234567800,
+                    duration: Some(40),
+                    session: 1,
+                },
+                RepoSync {
+                    repo: "guru".to_string(),
+                    start: 1234567841,
+                    duration: None,
+                    session: 1,
+                },
+                RepoSync {
+                    repo: "gentoo".to_string(),
+                    start: 1234567850,
+                    duration: Some(30),
+                    session: 2,
+                },
+            ]
+        );
+        assert_eq!(state.syncs_not_complete.len(), 1);
+        assert_eq!(state.syncs_not_complete["gentoo"], 1234567886);
+    }
+
+    #[test]
+    fn read_file_unmerges() {
+        let state = read_file_test("./tests/emerge.log/unmerge");
+
+        assert_eq!(
+            state.unmerges,
+            vec![
+                Unmerge {
+                    time: 1234567812,
+                    cpn: "category/package".to_string(),
+                    version: "1.2.3".to_string(),
+                    duration: 1,
+                    autoclean: true,
+                    session: 1,
+                },
+                Unmerge {
+                    time: 1234567830,
+                    cpn: "category/other".to_string(),
+                    version: "2.0".to_string(),
+                    duration: 2,
+                    autoclean: false,
+                    session: 2,
+                },
+            ]
+        );
+        assert_eq!(state.unmerges_not_complete.len(), 1);
+        assert!(state
+            .unmerges_not_complete
+            .contains_key("category/package-1.3"));
+    }
+
+    #[test]
+    fn read_metrics_atoms() {
+        let mut state = LogState::default();
+        for cpn in ["app/testing", "www-client/chromium"] {
+            state.add_merge(Merge {
+                time: 1234567890,
+                cpn: cpn.to_string(),
+                version: "0.0.0".to_string(),
+                is_binary: false,
+                duration: 60,
+                num: "1 of 1".to_string(),
+                session: 1,
+                target_root: "/".to_string(),
+                repository: String::new(),
+                slot: String::new(),
+            });
+        }
+
+        state.read_metrics("./tests/metrics", "./tests/build/");
+        let testing = &state.completed_atoms["app/testing"];
+        assert_eq!(testing.peak_rss, None);
+        assert_eq!(testing.log_size, Some(42));
+        let chromium = &state.completed_atoms["www-client/chromium"];
+        assert_eq!(chromium.peak_rss, Some(14680064));
+        assert_eq!(chromium.log_size, None);
+    }
+
+    #[test]
+    fn read_file_slots() {
+        let mut state = read_file_test("./tests/emerge.log/slots");
+
+        let atom = &state.completed_atoms["sys-devel/llvm"];
+        assert_eq!(atom.slots["sys-devel/llvm-15.0.7"], "15");
+        assert_eq!(atom.slots["sys-devel/llvm-18.1.6"], "18");
+        assert!(!atom.slots.contains_key("sys-devel/llvm-18.1.7"));
+        assert_eq!(state.emerges_not_complete["sys-devel/llvm-18.1.8"].slot, "");
+
+        state.read_vdb_slots("./tests/vdb");
+        let atom = &state.completed_atoms["sys-devel/llvm"];
+        assert_eq!(atom.slots["sys-devel/llvm-18.1.7"], "18");
+    }
+
+    #[test]
+    fn read_file_parallel() {
+        for file in ["./tests/emerge.log/sessions", "./tests/emerge.log/slots"] {
+            let sequential = read_file_test(file);
+            let size = plain_size(file).unwrap();
+            for chunks in [2, 3, 10] {
+                let mut state = LogState::default();
+                read_file_chunks(file, size, chunks, &mut state).unwrap();
+                assert_eq!(state.merges, sequential.merges, "{file} in {chunks}");
+                assert_eq!(state.sessions, sequential.sessions, "{file} in {chunks}");
+                assert_eq!(
+                    state.emerges_not_complete.keys().collect::<Vec<_>>(),
+                    sequential.emerges_not_complete.keys().collect::<Vec<_>>()
+                );
+                for (key, atom) in &sequential.completed_atoms {
+                    let parallel = &state.completed_atoms[key];
+                    assert_eq!(parallel.stats_line(), atom.stats_line());
+                    assert_eq!(parallel.versions, atom.versions);
+                    assert_eq!(parallel.slots, atom.slots);
+                    assert_eq!(parallel.last_time, atom.last_time);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_file_merges() {
+        let state = read_file_test("./tests/emerge.log/two_with_1binary");
 
-        assert_eq!(emerges_not_complete.len(), 0);
-        assert_eq!(completed_atoms.len(), 1); // Binary package are not added to it
+        assert_eq!(state.session_id(), 2);
+        assert_eq!(
+            state.merges,
+            vec![
+                Merge {
+                    time: 1234567800,
+                    cpn: "category/package".to_string(),
+                    version: "1.2.3".to_string(),
+                    is_binary: false,
+                    duration: 11,
+                    num: "1 of 1".to_string(),
+                    session: 1,
+                    target_root: "/".to_string(),
+                    repository: "gentoo".to_string(),
+                    slot: String::new(),
+                },
+                Merge {
+                    time: 1234567900,
+                    cpn: "category/package2".to_string(),
+                    version: "1.2.3".to_string(),
+                    is_binary: true,
+                    duration: 1,
+                    num: "1 of 1".to_string(),
+                    session: 2,
+                    target_root: "/".to_string(),
+                    repository: "gentoo".to_string(),
+                    slot: String::new(),
+                },
+            ]
+        );
     }
 
     #[test]
     fn read_file_binary_emerge_running() {
-        let (emerges_not_complete, completed_atoms) =
-            read_file_test("./tests/emerge.log/binary_running");
+        let state = read_file_test("./tests/emerge.log/binary_running");
 
-        assert_eq!(emerges_not_complete.len(), 1); // Binary is not done, so it has to be in emerges_not_complete
-        assert!(completed_atoms.is_empty());
+        assert_eq!(state.emerges_not_complete.len(), 1); // Binary is not done, so it has to be in emerges_not_complete
+        assert!(state.completed_atoms.is_empty());
+        assert!(state.merges.is_empty());
+        assert_eq!(state.sessions.len(), 1);
+        assert_eq!(state.sessions[0].status, SessionStatus::Running);
+        assert_eq!(state.sessions[0].attempted, vec!["category/package-1.2.3"]);
     }
 }
@@ -0,0 +1,253 @@
+#![warn(missing_docs)]
+
+//! Histories from other machines, used to improve the predictions
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{collections::HashMap, error::Error, str::FromStr};
+
+use crate::{
+    export::{read_export, ExportFormat},
+    package::Atom,
+    parse_file::{read_file, LogState},
+};
+
+/// Where to find the history of another machine, and how to use it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReferenceSource {
+    /// The name of the machine
+    pub name: String,
+    /// The emerge.log files of the machine, or the files created by `gls export` (`.csv` or `.jsonl`)
+    pub logs: Vec<String>,
+    /// How much time the local machine needs compared to this one.
+    /// A factor of 2 means the local machine is twice slower
+    pub factor: f64,
+    /// If true, the times are always added to the local ones.
+    /// Otherwise they are only used when the local history does not know the package
+    pub blend: bool,
+}
+
+impl FromStr for ReferenceSource {
+    type Err = String;
+
+    /// Parse a source from a list of `key=value` separated by commas
+    ///
+    /// The keys are `name`, `log` (can be repeated, at least one is needed), `factor` (default 1) and `mode` (`fallback` or `blend`, default `fallback`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut source = ReferenceSource {
+            name: String::new(),
+            logs: vec![],
+            factor: 1.0,
+            blend: false,
+        };
+
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or(format!("expected key=value, found '{part}'"))?;
+            match key {
+                "name" => source.name = value.to_string(),
+                "log" => source.logs.push(value.to_string()),
+                "factor" => {
+                    source.factor = value
+                        .parse()
+                        .map_err(|e| format!("invalid factor '{value}': {e}"))?;
+                    if source.factor <= 0.0 {
+                        return Err(format!("the factor must be positive, found {value}"));
+                    }
+                }
+                "mode" => match value {
+                    "fallback" => source.blend = false,
+                    "blend" => source.blend = true,
+                    _ => return Err(format!("unknown mode '{value}'")),
+                },
+                _ => return Err(format!("unknown key '{key}'")),
+            }
+        }
+
+        if source.logs.is_empty() {
+            return Err("at least one log is needed".to_string());
+        }
+        if source.name.is_empty() {
+            source.name = source.logs[0].clone();
+        }
+
+        Ok(source)
+    }
+}
+
+/// The history of another machine, once read
+pub struct Reference {
+    /// Where the history comes from
+    pub source: ReferenceSource,
+    /// The atoms read from the logs of the source, with their original times
+    pub atoms: HashMap<String, Atom>,
+}
+
+impl Reference {
+    /// Read all the logs of `source`
+    ///
+    /// The emerges still running in the logs are ignored.
+    pub fn load(source: &ReferenceSource) -> Result<Self, Box<dyn Error>> {
+        let mut state = LogState::default();
+        for log in &source.logs {
+            match ExportFormat::from_path(log) {
+                Some(format) => {
+                    for merge in read_export(log, format)? {
+                        state.add_merge(merge);
+                    }
+                }
+                None => read_file(log, &mut state)?,
+            }
+        }
+
+        Ok(Self {
+            source: source.clone(),
+            atoms: state.completed_atoms,
+        })
+    }
+}
+
+/// Return the atom to use for the prediction of `cpn`
+///
+/// * `cpn`: The package we want to know the time of
+/// * `local`: What the local history knows about `cpn`
+/// * `references`: The histories of the other machines. Their times are scaled to the local machine
+/// * `start`: When the emerge started, used if `local` does not exist
+///
+/// If `local` exists, only the references in blend mode are added to it.
+/// Otherwise, all the references that know `cpn` are used.
+pub fn lookup(
+    cpn: &str,
+    local: Option<&Atom>,
+    references: &[Reference],
+    start: u32,
+) -> Option<Atom> {
+    let mut result = local.cloned();
+    for reference in references {
+        if local.is_some() && !reference.source.blend {
+            continue;
+        }
+        if let Some(atom) = reference.atoms.get(cpn) {
+            let scaled = atom.scaled(reference.source.factor);
+            match result.as_mut() {
+                Some(r) => r.merge(&scaled),
+                None => {
+                    result = Some(Atom {
+                        last_time: start,
+                        ..scaled
+                    })
+                }
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_reference(factor: f64, blend: bool) -> Reference {
+        let mut atoms = HashMap::new();
+        atoms.insert("a/b".to_string(), Atom::new("a/b".to_string(), 100, 5));
+        Reference {
+            source: ReferenceSource {
+                name: "box".to_string(),
+                logs: vec![],
+                factor,
+                blend,
+            },
+            atoms,
+        }
+    }
+
+    #[test]
+    fn source_from_str() {
+        let source: ReferenceSource = "name=box,log=/a.log,log=/b.log,factor=2.5,mode=blend"
+            .parse()
+            .unwrap();
+        assert_eq!(source.name, "box");
+        assert_eq!(source.logs, vec!["/a.log", "/b.log"]);
+        assert_eq!(source.factor, 2.5);
+        assert!(source.blend);
+    }
+
+    #[test]
+    fn source_from_str_defaults() {
+        let source: ReferenceSource = "log=/a.log".parse().unwrap();
+        assert_eq!(source.name, "/a.log");
+        assert_eq!(source.factor, 1.0);
+        assert!(!source.blend);
+    }
+
+    #[test]
+    fn source_from_str_invalid() {
+        assert!("name=box".parse::<ReferenceSource>().is_err());
+        assert!("log=/a,factor=fast".parse::<ReferenceSource>().is_err());
+        assert!("log=/a,factor=-1".parse::<ReferenceSource>().is_err());
+        assert!("log=/a,mode=always".parse::<ReferenceSource>().is_err());
+    }
+
+    #[test]
+    fn load_reference() {
+        let source: ReferenceSource = "log=./tests/emerge.log/two_with_1binary".parse().unwrap();
+        let reference = Reference::load(&source).unwrap();
+        assert_eq!(reference.atoms.len(), 1);
+    }
+
+    #[test]
+    fn load_reference_export() {
+        let source: ReferenceSource = "log=./tests/export/two.csv".parse().unwrap();
+        let reference = Reference::load(&source).unwrap();
+        assert_eq!(reference.atoms.len(), 1);
+        assert_eq!(reference.atoms["category/package"].total_time, 11);
+    }
+
+    #[test]
+    fn lookup_fallback() {
+        let references = [create_reference(2.0, false)];
+        let atom = lookup("a/b", None, &references, 42).unwrap();
+        assert_eq!(atom.total_time, 200);
+        assert_eq!(atom.last_time, 42);
+        assert!(lookup("a/c", None, &references, 42).is_none());
+    }
+
+    #[test]
+    fn lookup_local_first() {
+        let references = [create_reference(2.0, false)];
+        let local = Atom::new("a/b".to_string(), 10, 7);
+        let atom = lookup("a/b", Some(&local), &references, 42).unwrap();
+        assert_eq!(atom.num_emerge, 1);
+        assert_eq!(atom.total_time, 10);
+        assert_eq!(atom.last_time, 7);
+    }
+
+    #[test]
+    fn lookup_blend() {
+        let references = [create_reference(0.5, true)];
+        let local = Atom::new("a/b".to_string(), 10, 7);
+        let atom = lookup("a/b", Some(&local), &references, 42).unwrap();
+        assert_eq!(atom.num_emerge, 2);
+        assert_eq!(atom.total_time, 60);
+        assert_eq!(atom.last_time, 7);
+    }
+}
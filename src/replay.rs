@@ -0,0 +1,134 @@
+#![warn(missing_docs)]
+
+//! Read the logs as they were at a moment of the past, to show what would have been printed then
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::error::Error;
+
+use crate::{
+    json::EmergeResume,
+    logfile::{for_each_line, open_log},
+    package::PackageInfo,
+    parse_file::{act_on_line, get_info, get_line_time, LogState},
+    report_error,
+    root::Root,
+};
+
+/// What the logs tell about the moment `at`
+pub struct Replay {
+    /// The moment replayed
+    pub at: u32,
+    /// What we learned from the lines written until `at`
+    pub state: LogState,
+    /// The packages started after `at` by the emerges running at `at`, in order
+    pub next: Vec<PackageInfo>,
+    /// If a line '*** terminating.' was found after `at`: the emerges running at `at` ended
+    ended: bool,
+}
+
+impl Replay {
+    /// Start the replay of the moment `at`
+    pub fn new(at: u32) -> Self {
+        Self {
+            at,
+            state: LogState::default(),
+            next: vec![],
+            ended: false,
+        }
+    }
+
+    /// Act on a line of the log: learn from it if it was written before `at`, or see what the emerge did next
+    fn act_on_line(&mut self, line: &str) {
+        match get_line_time(line) {
+            Some(time) if time > self.at => (),
+            _ => return act_on_line(line, &mut self.state),
+        }
+        if self.ended || self.state.emerges_not_complete.is_empty() {
+            return;
+        }
+        let rest = line.get(12..).unwrap_or("");
+        if rest.starts_with(" *** terminating.") {
+            self.ended = true;
+        } else if rest.starts_with(" >>> emerge (") {
+            self.next.extend(get_info(line));
+        }
+    }
+
+    /// Read the log at `file` (that can be compressed), after the logs already read
+    pub fn read_file(&mut self, file: &str) -> Result<(), Box<dyn Error>> {
+        for_each_line(open_log(file)?, |line| self.act_on_line(line))?;
+
+        Ok(())
+    }
+
+    /// Return the packages the emerges had to merge at `at`: the running ones, and the ones started after
+    ///
+    /// This replaces the list of mtimedb, that only knows the present.
+    pub fn resume(&self) -> Vec<EmergeResume> {
+        let mut running: Vec<&PackageInfo> = self.state.emerges_not_complete.values().collect();
+        running.sort_by_key(|p| p.time);
+        running
+            .into_iter()
+            .chain(&self.next)
+            .map(EmergeResume::from_package)
+            .collect()
+    }
+}
+
+/// Read all the logs of `root` as they were at `at`
+///
+/// The files that can not be read are reported (unless `skip_file`) and ignored.
+pub fn replay_root(root: &Root, skip_file: bool, at: u32) -> Replay {
+    let mut replay = Replay::new(at);
+    for path in &root.logs {
+        report_error(replay.read_file(path), path, skip_file);
+    }
+    replay.state.read_vdb_slots(&root.paths.vdb);
+    replay
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_during_emerge() {
+        // The second session started 1234567820 and emerged 2 packages
+        let mut replay = Replay::new(1234567825);
+        replay.read_file("./tests/emerge.log/sessions").unwrap();
+        assert_eq!(replay.state.sessions.len(), 2);
+        assert_eq!(replay.state.merges.len(), 1);
+        let running: Vec<&String> = replay.state.emerges_not_complete.keys().collect();
+        assert_eq!(running, ["category/package-1.2.3"]);
+        let resume: Vec<String> = replay.resume().into_iter().map(|r| r.full_name).collect();
+        assert_eq!(resume, ["category/package-1.2.3", "category/other-2.0"]);
+        assert_eq!(replay.resume()[1].category, "category");
+    }
+
+    #[test]
+    fn replay_after_end() {
+        // The third session started after the end of the second one
+        let mut replay = Replay::new(1234567845);
+        replay.read_file("./tests/emerge.log/sessions").unwrap();
+        assert!(replay.state.emerges_not_complete.is_empty());
+        assert!(replay.next.is_empty());
+    }
+}
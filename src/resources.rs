@@ -0,0 +1,282 @@
+#![warn(missing_docs)]
+
+//! Measure in /proc the resources used by the running builds
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{
+    collections::HashMap,
+    fs,
+    time::{Duration, Instant},
+};
+
+/// How long to wait between the two snapshots used to compute the CPU use
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The number of clock ticks per second in /proc/<pid>/stat (USER_HZ, that Linux always gives as 100)
+const TICKS_PER_SECOND: f64 = 100.0;
+
+/// The names of the processes counted as compiler jobs
+const COMPILERS: [&str; 14] = [
+    "cc1", "cc1plus", "cc1obj", "f951", "lto1", "rustc", "clang", "ld", "ld.bfd", "ld.gold",
+    "ld.lld", "mold", "compile", "javac",
+];
+
+/// What /proc tells about a process
+struct ProcStat {
+    /// The id of its parent
+    ppid: u32,
+    /// The name of the executable
+    comm: String,
+    /// The CPU time used (user and system), in clock ticks
+    ticks: u64,
+    /// The memory used, in kB
+    rss: u64,
+}
+
+impl ProcStat {
+    /// Read /proc/`pid`/stat and /proc/`pid`/status, in `proc`
+    fn read(proc: &str, pid: u32) -> Option<Self> {
+        let stat = fs::read_to_string(format!("{proc}/{pid}/stat")).ok()?;
+        // The name is between parentheses, and can contain spaces and parentheses
+        let (start, rest) = stat.rsplit_once(')')?;
+        let (_, comm) = start.split_once('(')?;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        // The fields are numbered from the state, the third one
+        let ppid = fields.get(1)?.parse().ok()?;
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+
+        // The kernel threads have no memory
+        let status = fs::read_to_string(format!("{proc}/{pid}/status")).unwrap_or_default();
+        let rss = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap_or(0);
+
+        Some(Self {
+            ppid,
+            comm: comm.to_string(),
+            ticks: utime + stime,
+            rss,
+        })
+    }
+
+    /// Return true if the process is a compiler or a linker
+    fn is_job(&self) -> bool {
+        COMPILERS
+            .iter()
+            .any(|c| (self.comm == *c) || self.comm.starts_with(&format!("{c}-")))
+    }
+}
+
+/// The processes running at one moment
+pub struct Snapshot {
+    /// When the snapshot was taken
+    time: Instant,
+    /// The processes, by id
+    stats: HashMap<u32, ProcStat>,
+}
+
+impl Snapshot {
+    /// Read all the processes in `proc`
+    pub fn read(proc: &str) -> Self {
+        let time = Instant::now();
+        let stats = fs::read_dir(proc)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                    .filter_map(|pid| Some((pid, ProcStat::read(proc, pid)?)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self { time, stats }
+    }
+
+    /// Return the ids of `top` and of all their descendants
+    fn tree(&self, top: &[u32]) -> Vec<u32> {
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (pid, stat) in &self.stats {
+            children.entry(stat.ppid).or_default().push(*pid);
+        }
+
+        let mut result: Vec<u32> = top
+            .iter()
+            .filter(|pid| self.stats.contains_key(pid))
+            .copied()
+            .collect();
+        let mut i = 0;
+        while i < result.len() {
+            if let Some(c) = children.get(&result[i]) {
+                result.extend(
+                    c.iter()
+                        .filter(|pid| !result.contains(pid))
+                        .collect::<Vec<_>>(),
+                );
+            }
+            i += 1;
+        }
+        result
+    }
+}
+
+/// The resources used by a build: the one of the process building it and of all its descendants
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Resources {
+    /// The number of compilers and linkers running
+    pub jobs: usize,
+    /// The memory used, in kB
+    pub rss: u64,
+    /// The CPU used between the two snapshots, in percent of one CPU
+    pub cpu: f64,
+}
+
+impl Resources {
+    /// Measure the resources used by the processes `top` and their descendants
+    ///
+    /// * `before`, `after`: Two snapshots, the CPU used is the one between them
+    ///
+    /// The jobs and the memory are the ones of `after`.
+    pub fn measure(before: &Snapshot, after: &Snapshot, top: &[u32]) -> Self {
+        let mut resources = Self::default();
+        let mut ticks = 0;
+        for pid in after.tree(top) {
+            let stat = &after.stats[&pid];
+            if stat.is_job() {
+                resources.jobs += 1;
+            }
+            resources.rss += stat.rss;
+            // A process started after the first snapshot used all its time in between
+            let previous = before.stats.get(&pid).map_or(0, |s| s.ticks);
+            ticks += stat.ticks.saturating_sub(previous);
+        }
+
+        let elapsed = after
+            .time
+            .saturating_duration_since(before.time)
+            .as_secs_f64();
+        if elapsed > 0.0 {
+            resources.cpu = ticks as f64 / TICKS_PER_SECOND / elapsed * 100.0;
+        }
+        resources
+    }
+
+    /// Return the resources in a few words, for the status
+    ///
+    /// # Examples
+    /// `3 jobs, 1.2 GB, 250% CPU`
+    pub fn describe(&self) -> String {
+        format!(
+            "{} job{}, {}, {:.0}% CPU",
+            self.jobs,
+            if self.jobs == 1 { "" } else { "s" },
+            format_memory(self.rss),
+            self.cpu
+        )
+    }
+}
+
+/// Format a quantity of memory given in kB
+///
+/// # Examples
+/// `512 kB`, `300 MB` or `1.2 GB`
+pub fn format_memory(kb: u64) -> String {
+    if kb < 1024 {
+        format!("{kb} kB")
+    } else if kb < 1024 * 1024 {
+        format!("{} MB", kb / 1024)
+    } else {
+        format!("{:.1} GB", kb as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// Return the memory of the machine in kB, read in `proc`/meminfo
+pub fn total_memory(proc: &str) -> Option<u64> {
+    fs::read_to_string(format!("{proc}/meminfo"))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_stat() {
+        let stat = ProcStat::read("./tests/proc", 202).unwrap();
+        assert_eq!(stat.ppid, 201);
+        assert_eq!(stat.comm, "cc1plus");
+        assert_eq!(stat.ticks, 200);
+        assert_eq!(stat.rss, 524288);
+        assert!(stat.is_job());
+
+        let sandbox = ProcStat::read("./tests/proc", 200).unwrap();
+        assert_eq!(sandbox.comm, "sandbox (x)");
+        assert!(!sandbox.is_job());
+        assert!(ProcStat::read("./tests/proc", 1).is_none());
+    }
+
+    #[test]
+    fn snapshot_tree() {
+        let snapshot = Snapshot::read("./tests/proc");
+        let mut tree = snapshot.tree(&[200]);
+        tree.sort();
+        assert_eq!(tree, vec![200, 201, 202, 203]);
+        assert_eq!(snapshot.tree(&[203]), vec![203]);
+        assert!(snapshot.tree(&[999]).is_empty());
+    }
+
+    #[test]
+    fn measure_resources() {
+        let mut before = Snapshot::read("./tests/proc");
+        let mut after = Snapshot::read("./tests/proc");
+        after.time = before.time + Duration::from_secs(2);
+        // 203 was not started yet, and 202 used 1s since
+        before.stats.remove(&203);
+        before.stats.get_mut(&202).unwrap().ticks -= 100;
+
+        let resources = Resources::measure(&before, &after, &[200]);
+        assert_eq!(resources.jobs, 2);
+        assert_eq!(resources.rss, 4096 + 8192 + 524288 + 262144);
+        // 1s for 202, and 0.5s for 203, in 2s
+        assert_eq!(resources.cpu, 75.0);
+        assert_eq!(resources.describe(), "2 jobs, 780 MB, 75% CPU");
+    }
+
+    #[test]
+    fn memory_total() {
+        assert_eq!(total_memory("./tests/proc"), Some(16318412));
+        assert_eq!(total_memory("./tests/do_not_exist"), None);
+    }
+
+    #[test]
+    fn memory_format() {
+        assert_eq!(format_memory(512), "512 kB");
+        assert_eq!(format_memory(300 * 1024), "300 MB");
+        assert_eq!(format_memory(1258291), "1.2 GB");
+    }
+}
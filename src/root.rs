@@ -0,0 +1,288 @@
+#![warn(missing_docs)]
+
+//! Declaration of the roots (the host, chroots, ...) we read the logs from
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::str::FromStr;
+
+use crate::useful::correct_path;
+
+/// Where portage puts its files, relative to a root
+#[derive(Clone, Debug, PartialEq)]
+pub struct Paths {
+    /// The file with the list of the packages to emerge
+    pub mtimedb: String,
+    /// The folder of the build logs (need split-log in FEATURES)
+    pub build_log: String,
+    /// The folder of the installed packages (VDB), used to read their slot
+    pub vdb: String,
+    /// The folder where portage builds the packages (PORTAGE_TMPDIR/portage), with their lock files
+    pub tmpdir: String,
+    /// The file where `gls watch` records the peak memory of the builds (see [`crate::metrics`])
+    pub metrics: String,
+}
+
+impl Default for Paths {
+    /// The paths used by portage
+    fn default() -> Self {
+        Self {
+            mtimedb: "/var/cache/edb/mtimedb".to_string(),
+            build_log: "/var/log/portage/build/".to_string(),
+            vdb: "/var/db/pkg/".to_string(),
+            tmpdir: "/var/tmp/portage/".to_string(),
+            metrics: "/var/lib/genlogsum/metrics".to_string(),
+        }
+    }
+}
+
+impl Paths {
+    /// Return the paths inside of `fakeroot` (see [`correct_path`])
+    pub fn inside(&self, fakeroot: &str) -> Self {
+        let inside = |path: &str| {
+            let mut corrected = String::new();
+            correct_path(fakeroot, path, &mut corrected);
+            corrected
+        };
+        Self {
+            mtimedb: inside(&self.mtimedb),
+            build_log: inside(&self.build_log),
+            vdb: inside(&self.vdb),
+            tmpdir: inside(&self.tmpdir),
+            metrics: inside(&self.metrics),
+        }
+    }
+}
+
+/// Everything we need to know about a root to show its emerges
+#[derive(Clone, Debug, PartialEq)]
+pub struct Root {
+    /// The name shown when using `--show-root`. Empty for the host
+    pub name: String,
+    /// The folder acting as root
+    pub path: String,
+    /// The emerge.log files to read
+    pub logs: Vec<String>,
+    /// The files of portage in this root
+    pub paths: Paths,
+}
+
+impl Root {
+    /// Create a root using the paths of portage inside of `fakeroot`
+    ///
+    /// * `fakeroot`: The folder acting as root
+    /// * `files`: The emerge.log files, relative to `fakeroot` (see [`correct_path`])
+    /// * `paths`: The other files, relative to `fakeroot`
+    pub fn from_fakeroot(fakeroot: &str, files: &[String], paths: &Paths) -> Self {
+        let logs = files
+            .iter()
+            .map(|file| {
+                let mut path = String::new();
+                correct_path(fakeroot, file, &mut path);
+                path
+            })
+            .collect();
+
+        Self {
+            name: default_name(fakeroot),
+            path: fakeroot.to_string(),
+            logs,
+            paths: paths.inside(fakeroot),
+        }
+    }
+}
+
+impl Root {
+    /// Return the name of the root, or its path if it has no name
+    pub fn label(&self) -> &str {
+        if self.name.is_empty() {
+            &self.path
+        } else {
+            &self.name
+        }
+    }
+}
+
+/// The name of a root is the last component of its path, or nothing for `/`
+fn default_name(path: &str) -> String {
+    if path == "/" {
+        return String::new();
+    }
+    std::path::Path::new(path)
+        .components()
+        .next_back()
+        .and_then(|c| c.as_os_str().to_str())
+        .unwrap_or("")
+        .to_string()
+}
+
+impl FromStr for Root {
+    type Err = String;
+
+    /// Parse a root from a list of `key=value` separated by commas
+    ///
+    /// The keys are `name`, `path`, `log` (can be repeated), `mtimedb`, `build-log`, `vdb`, `tmpdir` and `metrics`.
+    /// Except `name`, the values are paths used as-is (they are not put under `path`).
+    /// Missing values are replaced by the one given by [`Root::from_fakeroot`], with the default [`Paths`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut path = None;
+        let mut logs: Vec<String> = Vec::new();
+        let mut mtimedb = None;
+        let mut build_log = None;
+        let mut vdb = None;
+        let mut tmpdir = None;
+        let mut metrics = None;
+
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or(format!("expected key=value, found '{part}'"))?;
+            let value = value.to_string();
+            match key {
+                "name" => name = Some(value),
+                "path" => path = Some(value),
+                "log" => logs.push(value),
+                "mtimedb" => mtimedb = Some(value),
+                "build-log" => build_log = Some(value),
+                "vdb" => vdb = Some(value),
+                "tmpdir" => tmpdir = Some(value),
+                "metrics" => metrics = Some(value),
+                _ => return Err(format!("unknown key '{key}'")),
+            }
+        }
+
+        let path = path.unwrap_or("/".to_string());
+        let mut root = Root::from_fakeroot(
+            &path,
+            &["/var/log/emerge.log".to_string()],
+            &Paths::default(),
+        );
+        if let Some(n) = name {
+            root.name = n;
+        }
+        if !logs.is_empty() {
+            root.logs = logs;
+        }
+        if let Some(m) = mtimedb {
+            root.paths.mtimedb = m;
+        }
+        if let Some(b) = build_log {
+            root.paths.build_log = b;
+        }
+        if let Some(v) = vdb {
+            root.paths.vdb = v;
+        }
+        if let Some(t) = tmpdir {
+            root.paths.tmpdir = t;
+        }
+        if let Some(m) = metrics {
+            root.paths.metrics = m;
+        }
+
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_from_fakeroot_host() {
+        let root =
+            Root::from_fakeroot("/", &["/var/log/emerge.log".to_string()], &Paths::default());
+        assert_eq!(root.name, "");
+        assert_eq!(root.logs, vec!["/var/log/emerge.log"]);
+        assert_eq!(root.paths.mtimedb, "/var/cache/edb/mtimedb");
+        assert_eq!(root.paths.build_log, "/var/log/portage/build/");
+    }
+
+    #[test]
+    fn root_from_fakeroot_chroot() {
+        let files = [
+            "/var/log/emerge.log".to_string(),
+            "./emerge.log".to_string(),
+        ];
+        let root = Root::from_fakeroot("/mnt/gentoo/", &files, &Paths::default());
+        assert_eq!(root.name, "gentoo");
+        assert_eq!(
+            root.logs,
+            vec!["/mnt/gentoo/var/log/emerge.log", "./emerge.log"]
+        );
+        assert_eq!(root.paths.build_log, "/mnt/gentoo/var/log/portage/build/");
+        assert_eq!(root.paths.tmpdir, "/mnt/gentoo/var/tmp/portage/");
+        assert_eq!(root.paths.metrics, "/mnt/gentoo/var/lib/genlogsum/metrics");
+        assert_eq!(root.paths.mtimedb, "/mnt/gentoo/var/cache/edb/mtimedb");
+    }
+
+    #[test]
+    fn root_from_fakeroot_paths() {
+        let paths = Paths {
+            mtimedb: "./tests/mtimedb/1".to_string(),
+            ..Paths::default()
+        };
+        let root = Root::from_fakeroot("/", &[], &paths);
+        assert_eq!(root.paths.mtimedb, "./tests/mtimedb/1");
+        assert_eq!(root.paths.vdb, "/var/db/pkg/");
+        assert_eq!(root.paths.tmpdir, "/var/tmp/portage/");
+    }
+
+    #[test]
+    fn root_label() {
+        let mut root = Root::from_fakeroot("/", &[], &Paths::default());
+        assert_eq!(root.label(), "/");
+        root.name = "host".to_string();
+        assert_eq!(root.label(), "host");
+    }
+
+    #[test]
+    fn root_from_str_full() {
+        let root: Root =
+            "name=arm,path=/mnt/arm,log=/a.log,log=/b.log,mtimedb=/m,build-log=/b/,tmpdir=/t/"
+                .parse()
+                .unwrap();
+        assert_eq!(root.name, "arm");
+        assert_eq!(root.path, "/mnt/arm");
+        assert_eq!(root.logs, vec!["/a.log", "/b.log"]);
+        assert_eq!(root.paths.mtimedb, "/m");
+        assert_eq!(root.paths.build_log, "/b/");
+        assert_eq!(root.paths.tmpdir, "/t/");
+    }
+
+    #[test]
+    fn root_from_str_defaults() {
+        let root: Root = "path=/mnt/arm".parse().unwrap();
+        assert_eq!(
+            root,
+            Root::from_fakeroot(
+                "/mnt/arm",
+                &["/var/log/emerge.log".to_string()],
+                &Paths::default()
+            )
+        );
+    }
+
+    #[test]
+    fn root_from_str_invalid() {
+        assert!("name".parse::<Root>().is_err());
+        assert!("foo=bar".parse::<Root>().is_err());
+    }
+}
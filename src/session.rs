@@ -0,0 +1,254 @@
+#![warn(missing_docs)]
+
+//! Store the emerge sessions (one for each call to emerge)
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use crate::package::{Atom, Merge};
+
+/// How a session ended
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionStatus {
+    /// No line ended the session yet
+    Running,
+    /// The session ended with `*** exiting successfully.`
+    Success,
+    /// The session ended with `*** exiting unsuccessfully`, with the status given
+    Failure(String),
+    /// The session ended with `*** terminating.`, without saying if it was a success
+    Terminated,
+    /// Another session started before this one ended (emerge was killed)
+    Interrupted,
+}
+
+/// One call to emerge, from `Started emerge on:` to `*** terminating.`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    /// The number of the session in the log, starting at 1
+    pub id: u32,
+    /// When the session started
+    pub start: u32,
+    /// When the session ended
+    pub end: Option<u32>,
+    /// The arguments given to emerge
+    pub args: String,
+    /// The full name of the packages emerge started
+    pub attempted: Vec<String>,
+    /// The full name of the packages completed
+    pub merged: Vec<String>,
+    /// The full name of the packages started but not completed when the session ended
+    pub failed: Vec<String>,
+    /// How the session ended
+    pub status: SessionStatus,
+}
+
+impl Session {
+    /// Create a new session, started at `start`
+    pub fn new(id: u32, start: u32) -> Self {
+        Self {
+            id,
+            start,
+            end: None,
+            args: String::new(),
+            attempted: vec![],
+            merged: vec![],
+            failed: vec![],
+            status: SessionStatus::Running,
+        }
+    }
+
+    /// Return true if the session ended: it was terminated, or another session started before
+    pub fn has_ended(&self) -> bool {
+        self.end.is_some() || (self.status == SessionStatus::Interrupted)
+    }
+
+    /// Return the time between the start and the end of the session (or `now` if it did not end)
+    pub fn wall_time(&self, now: u32) -> u32 {
+        self.end.unwrap_or(now).saturating_sub(self.start)
+    }
+
+    /// Return the status as a short text
+    pub fn status_text(&self) -> String {
+        match &self.status {
+            SessionStatus::Running => "running".to_string(),
+            SessionStatus::Success => "success".to_string(),
+            SessionStatus::Failure(code) => format!("failure ({code})"),
+            SessionStatus::Terminated => "terminated".to_string(),
+            SessionStatus::Interrupted => "interrupted".to_string(),
+        }
+    }
+
+    /// Return a one line summary of the session
+    ///
+    /// * `now`: The current time, used for the wall time of a running session
+    ///
+    /// # Examples
+    /// `12  2024-09-30 14:00:03  1h 2m  15/16 merged  failure (1)  --update @world`
+    pub fn summary(&self, now: u32) -> String {
+        let mut wall = String::new();
+        Atom::convert_text(self.wall_time(now) as f64, &mut wall);
+        format!(
+            "{}  {}  {}  {}/{} merged  {}  {}",
+            self.id,
+            format_date(self.start),
+            wall.trim_end(),
+            self.merged.len(),
+            self.attempted.len(),
+            self.status_text(),
+            self.args
+        )
+    }
+
+    /// Return a detailed description of the session, with one line per package
+    ///
+    /// * `merges`: The merges of the log, used to show the time of each package
+    /// * `now`: The current time, used for the wall time of a running session
+    pub fn details(&self, merges: &[Merge], now: u32) -> String {
+        let mut wall = String::new();
+        Atom::convert_text(self.wall_time(now) as f64, &mut wall);
+        let end = match self.end {
+            Some(t) => format_date(t),
+            None => "-".to_string(),
+        };
+
+        let mut out = format!(
+            "Session {}\nCommand: emerge {}\nStarted: {}\nEnded: {end}\nWall time: {}\nStatus: {}\n",
+            self.id,
+            self.args,
+            format_date(self.start),
+            wall.trim_end(),
+            self.status_text()
+        );
+        out.push_str(&format!(
+            "Packages: {} attempted, {} merged, {} failed\n",
+            self.attempted.len(),
+            self.merged.len(),
+            self.failed.len()
+        ));
+
+        for full_name in &self.attempted {
+            let merge = merges.iter().find(|m| {
+                (m.session == self.id) && (format!("{}-{}", m.cpn, m.version) == *full_name)
+            });
+            let status = match merge {
+                Some(m) => {
+                    let mut time = String::new();
+                    Atom::convert_text(m.duration as f64, &mut time);
+                    let kind = if m.is_binary { "binary, " } else { "" };
+                    format!("merged ({kind}{})", time.trim_end())
+                }
+                None if self.failed.contains(full_name) => "failed".to_string(),
+                None => "not completed".to_string(),
+            };
+            out.push_str(&format!("  {full_name}: {status}\n"));
+        }
+
+        out
+    }
+}
+
+/// Format a timestamp as a local date
+pub fn format_date(time: u32) -> String {
+    match chrono::DateTime::from_timestamp(time.into(), 0) {
+        Some(date) => date
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => time.to_string(),
+    }
+}
+
+/// Parse a local date, as written by [`format_date`] (the seconds are optional), or a timestamp
+///
+/// # Examples
+/// `2024-09-30 14:00`, `2024-09-30 14:00:03` or `1727697600`
+pub fn parse_date(text: &str) -> Result<u32, String> {
+    if let Ok(timestamp) = text.parse() {
+        return Ok(timestamp);
+    }
+    let date = chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M"))
+        .map_err(|e| format!("invalid date '{text}': {e}"))?;
+    let local = date
+        .and_local_timezone(chrono::Local)
+        .earliest()
+        .ok_or(format!("'{text}' does not exist in the local time zone"))?;
+    u32::try_from(local.timestamp()).map_err(|_| format!("'{text}' is out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_parse() {
+        assert_eq!(parse_date("1234567890"), Ok(1234567890));
+        assert_eq!(parse_date(&format_date(1234567890)), Ok(1234567890));
+        let minutes = format_date(1234567860);
+        assert_eq!(parse_date(&minutes[..16]), Ok(1234567860));
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    fn create_session() -> Session {
+        let mut session = Session::new(2, 100);
+        session.args = "--oneshot a/b".to_string();
+        session.attempted = vec!["a/b-1".to_string(), "a/c-2".to_string()];
+        session.merged = vec!["a/b-1".to_string()];
+        session.failed = vec!["a/c-2".to_string()];
+        session.end = Some(100 + 3 * 60);
+        session.status = SessionStatus::Failure("1".to_string());
+        session
+    }
+
+    #[test]
+    fn session_wall_time() {
+        let mut session = Session::new(1, 100);
+        assert_eq!(session.wall_time(160), 60);
+        session.end = Some(130);
+        assert_eq!(session.wall_time(160), 30);
+    }
+
+    #[test]
+    fn session_summary() {
+        let summary = create_session().summary(0);
+        assert!(summary.starts_with("2  "));
+        assert!(summary.ends_with("  3m  1/2 merged  failure (1)  --oneshot a/b"));
+    }
+
+    #[test]
+    fn session_details() {
+        let merges = [Merge {
+            time: 100,
+            cpn: "a/b".to_string(),
+            version: "1".to_string(),
+            is_binary: false,
+            duration: 120,
+            num: "1 of 2".to_string(),
+            session: 2,
+            target_root: "/".to_string(),
+            repository: "gentoo".to_string(),
+            slot: String::new(),
+        }];
+        let details = create_session().details(&merges, 0);
+        assert!(details.starts_with("Session 2\nCommand: emerge --oneshot a/b\n"));
+        assert!(details.contains("Packages: 2 attempted, 1 merged, 1 failed\n"));
+        assert!(details.ends_with("  a/b-1: merged (2m)\n  a/c-2: failed\n"));
+    }
+}
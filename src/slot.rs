@@ -0,0 +1,105 @@
+#![warn(missing_docs)]
+
+//! Find the slot of the packages, so that the slots installed in parallel have their own times
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::fs;
+
+use crate::atom::cpn_len;
+
+/// Remove the sub-slot from `slot` (`18/18.1` gives `18`)
+fn main_slot(slot: &str) -> &str {
+    slot.split('/').next().unwrap_or(slot).trim()
+}
+
+/// Read the slot of an installed package from the VDB (`/var/db/pkg/category/name-version/SLOT`)
+///
+/// * `vdb`: The folder of the VDB (see [`Root::vdb`](crate::root::Root::vdb))
+/// * `full_name`: The package, with its version
+///
+/// Return `None` if the package is not installed.
+pub fn read_vdb_slot(vdb: &str, full_name: &str) -> Option<String> {
+    let path = format!("{}/{full_name}/SLOT", vdb.trim_end_matches('/'));
+    let content = fs::read_to_string(path).ok()?;
+    let slot = main_slot(&content);
+    if slot.is_empty() {
+        return None;
+    }
+    Some(slot.to_string())
+}
+
+/// Split an atom with a slot (`>=sys-devel/llvm-18:18/18.1::gentoo`) in its cpn (`sys-devel/llvm`) and its slot (`18`)
+///
+/// Return `None` if the atom has no slot.
+pub fn split_slot_atom(atom: &str) -> Option<(&str, &str)> {
+    let atom = atom.split("::").next()?;
+    let (package, slot) = atom.split_once(':')?;
+    let package = package.trim_start_matches(['<', '>', '=', '~']);
+    let slot = main_slot(slot).trim_end_matches('=');
+    if slot.is_empty() || slot == "*" {
+        return None;
+    }
+    Some((&package[..cpn_len(package)], slot))
+}
+
+/// Return the slot given for `cpn` in the arguments of emerge (`emerge --oneshot sys-devel/llvm:18`)
+pub fn slot_from_args(args: &str, cpn: &str) -> Option<String> {
+    args.split_whitespace()
+        .filter_map(split_slot_atom)
+        .find(|(package, _)| *package == cpn)
+        .map(|(_, slot)| slot.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vdb_slot() {
+        assert_eq!(
+            read_vdb_slot("./tests/vdb/", "sys-devel/llvm-18.1.7").unwrap(),
+            "18"
+        );
+        assert!(read_vdb_slot("./tests/vdb", "sys-devel/llvm-15.0.7").is_none());
+    }
+
+    #[test]
+    fn slot_atom() {
+        assert_eq!(
+            split_slot_atom("sys-devel/llvm:18"),
+            Some(("sys-devel/llvm", "18"))
+        );
+        assert_eq!(
+            split_slot_atom(">=dev-lang/python-3.12.1:3.12/3.12t::gentoo"),
+            Some(("dev-lang/python", "3.12"))
+        );
+        assert!(split_slot_atom("dev-lang/rust::gentoo").is_none());
+        assert!(split_slot_atom("dev-lang/rust:=").is_none());
+        assert!(split_slot_atom("@world").is_none());
+    }
+
+    #[test]
+    fn args_slot() {
+        let args = "--oneshot --jobs 2 sys-devel/llvm:18 dev-lang/rust";
+        assert_eq!(slot_from_args(args, "sys-devel/llvm").unwrap(), "18");
+        assert!(slot_from_args(args, "dev-lang/rust").is_none());
+    }
+}
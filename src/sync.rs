@@ -0,0 +1,141 @@
+#![warn(missing_docs)]
+
+//! Store the synchronisations of the repositories (`emerge --sync`)
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use crate::{session::format_date, useful::format_duration};
+
+/// The synchronisation of one repository
+#[derive(Clone, Debug, PartialEq)]
+pub struct RepoSync {
+    /// The name of the repository
+    pub repo: String,
+    /// When the synchronisation started
+    pub start: u32,
+    /// The time it took, if it completed
+    pub duration: Option<u32>,
+    /// The emerge session the synchronisation belongs to
+    pub session: u32,
+}
+
+impl RepoSync {
+    /// Return the line shown by `gls sync-history`
+    ///
+    /// # Examples
+    /// `2024-09-30 14:00:03  gentoo  42s` or `2024-09-30 14:00:03  guru  failed`
+    pub fn history_line(&self) -> String {
+        let status = match self.duration {
+            Some(d) => format_duration(d),
+            None => "failed".to_string(),
+        };
+        format!("{}  {}  {status}", format_date(self.start), self.repo)
+    }
+}
+
+/// Return the average duration of the completed synchronisations of `repo`, if there is one
+pub fn average_duration(repo: &str, syncs: &[RepoSync]) -> Option<u32> {
+    let durations: Vec<u32> = syncs
+        .iter()
+        .filter(|s| s.repo == repo)
+        .filter_map(|s| s.duration)
+        .collect();
+    if durations.is_empty() {
+        return None;
+    }
+    Some(durations.iter().sum::<u32>() / durations.len() as u32)
+}
+
+/// Return the name of the repository from the lines starting a synchronisation
+///
+/// * `rest`: The line, without the time
+///
+/// Handle `>>> Syncing repository 'gentoo' into '/var/db/repos/gentoo'...`, and for logs that do not have it,
+/// `>>> Starting rsync with rsync://host/gentoo-portage`, `>>> Starting git pull in /var/db/repos/guru...` or `>>> Git pull in /var/db/repos/guru`.
+pub fn get_sync_repo(rest: &str) -> Option<String> {
+    if let Some(r) = rest.strip_prefix(">>> Syncing repository '") {
+        return Some(r[..r.find('\'')?].to_string());
+    }
+
+    let location = rest
+        .strip_prefix(">>> Starting rsync with ")
+        .or(rest.strip_prefix(">>> Starting git pull in "))
+        .or(rest.strip_prefix(">>> Git pull in "))?;
+    let location = location.trim_end_matches("...").trim_end_matches('/');
+    let name = &location[location.rfind('/').map(|i| i + 1).unwrap_or(0)..];
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sync_repo_syncing() {
+        let line = ">>> Syncing repository 'gentoo' into '/var/db/repos/gentoo'...";
+        assert_eq!(get_sync_repo(line).unwrap(), "gentoo");
+    }
+
+    #[test]
+    fn sync_repo_old_lines() {
+        let line = ">>> Starting rsync with rsync://rsync.gentoo.org/gentoo-portage";
+        assert_eq!(get_sync_repo(line).unwrap(), "gentoo-portage");
+        let line = ">>> Starting git pull in /var/db/repos/guru...";
+        assert_eq!(get_sync_repo(line).unwrap(), "guru");
+        let line = ">>> Git pull in /var/db/repos/guru/";
+        assert_eq!(get_sync_repo(line).unwrap(), "guru");
+        assert!(get_sync_repo(">>> emerge (1 of 1) a/b-1 to /").is_none());
+    }
+
+    #[test]
+    fn sync_average_duration() {
+        let create = |repo: &str, duration| RepoSync {
+            repo: repo.to_string(),
+            start: 0,
+            duration,
+            session: 1,
+        };
+        let syncs = [
+            create("gentoo", Some(40)),
+            create("gentoo", None),
+            create("gentoo", Some(20)),
+            create("guru", Some(5)),
+        ];
+        assert_eq!(average_duration("gentoo", &syncs), Some(30));
+        assert_eq!(average_duration("guru", &syncs), Some(5));
+        assert_eq!(average_duration("other", &syncs), None);
+    }
+
+    #[test]
+    fn sync_history_line() {
+        let mut sync = RepoSync {
+            repo: "gentoo".to_string(),
+            start: 0,
+            duration: Some(42),
+            session: 1,
+        };
+        assert!(sync.history_line().ends_with("  gentoo  42s"));
+        sync.duration = None;
+        assert!(sync.history_line().ends_with("  gentoo  failed"));
+    }
+}
@@ -21,7 +21,18 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 // // // // // // // // // // // // // // // // // // // // // // // //
 
-use clap::{Args, Parser};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    clock::{Clock, FixedClock, SystemClock},
+    export::ExportFormat,
+    logfile::with_rotated,
+    package::Atom,
+    reference::{Reference, ReferenceSource},
+    report_error,
+    root::{Paths, Root},
+    session::parse_date,
+};
 
 /// Enum type for the time of an emerge and its relashionship with the previous times of the package
 pub enum Over {
@@ -41,10 +52,28 @@ pub enum LineType {
     Start,
     /// If the line corresponds to the merge of an emerge
     MergeBinary,
+    /// If the line is another step of an emerge ('=== (x of y) Cleaning (...)', with the path of the ebuild)
+    Build,
     /// If the line is the completed emerge
     End,
     /// If the line signal termination
     Term,
+    /// If the line is the start of an emerge session ('Started emerge on:')
+    Session,
+    /// If the line gives the arguments of the session ('*** emerge ...')
+    Command,
+    /// If the line gives the status of the session ('*** exiting ...')
+    Exit,
+    /// If the line starts the synchronisation of a repository ('>>> Syncing repository ...')
+    SyncStart,
+    /// If the line completes the synchronisation of a repository ('=== Sync completed for ...')
+    SyncEnd,
+    /// If the line announces the removal of the old versions of a package ('>>> AUTOCLEAN: ...')
+    Autoclean,
+    /// If the line starts the unmerge of a package ('=== Unmerging... (...)')
+    UnmergeStart,
+    /// If the line ends the unmerge of a package ('>>> unmerge success: ...' or '!!! unmerge FAILURE: ...')
+    UnmergeEnd,
     /// If the line is not from the previous 3 types
     Unknow,
 }
@@ -57,49 +86,214 @@ pub enum LineType {
 )]
 /// Structures to store the configuration and arguments given from the command line
 pub struct Arguments {
-    #[arg(short, long, default_value = "/var/log/emerge.log", num_args(1..))]
+    #[command(subcommand)]
+    /// What to do. Without command, show the status of the running emerges
+    pub command: Option<Command>,
+
+    #[arg(short, long, default_value = "/var/log/emerge.log", num_args(1..), global = true)]
     /// Add a file to be read.
     pub files: Vec<String>,
 
-    #[arg(long, default_value = "/", num_args(1..), verbatim_doc_comment)]
+    #[arg(long, default_value = "/", num_args(1..), verbatim_doc_comment, global = true)]
     /// Select a folder to act as root.
     ///
     /// Should be a folder where you can chroot in as we will use the paths fakeroot/file and fakeroot/var/cache/edb/mtimedb.  
     ///
     /// This option is chained with \<FILES\>, meaning "-f foo.log foo/bar.log --fakeroots /foo / bar" will search for:  
     ///     /foo/foo.log, /foo/foo/bar.log, /bar/foo.log, /bar/foo/bar.log
+    ///
+    /// Ignored if at least one root is declared with --root.
     pub fakeroots: Vec<String>,
 
+    #[arg(
+        long = "root",
+        value_name = "SPEC",
+        verbatim_doc_comment,
+        global = true
+    )]
+    /// Declare a root explicitly, with its own name and paths.
+    ///
+    /// SPEC is a list of key=value separated by commas, with the keys:
+    ///     name, path, log (can be repeated), mtimedb, build-log, vdb, tmpdir, metrics
+    /// Paths are used as-is, and the missing ones default to the usual location under path.
+    /// For example: "--root name=arm,path=/mnt/arm,log=/mnt/arm/var/log/portage/emerge.log"
+    pub roots: Vec<Root>,
+
+    #[arg(
+        long = "reference",
+        value_name = "SPEC",
+        verbatim_doc_comment,
+        global = true
+    )]
+    /// Use the history of another machine to improve the predictions.
+    ///
+    /// SPEC is a list of key=value separated by commas, with the keys:
+    ///     name, log (can be repeated), factor, mode
+    /// The times read are multiplied by factor (2 means this machine is twice slower than the other one).
+    /// With mode=fallback (the default), they are used only for packages never emerged here.
+    /// With mode=blend, they are always added to the local times.
+    /// For example: "--reference name=buildbox,log=/srv/buildbox/emerge.log,factor=2.7"
+    pub references: Vec<ReferenceSource>,
+
     #[command(flatten)]
     /// Format of the output, as in, show all packages and their time, show the time until the end, or neither.
     pub format: Format,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     /// Read the completion rate from the log.
     /// Your portage need split-log in FEATURES.
     pub read_ninja: bool,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     /// Print the name of root we used.
     pub show_root: bool,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     /// If an error was found while reading a file, do not report the error.
     pub skip_file: bool,
+
+    #[arg(long, global = true)]
+    /// Also read the logs rotated by logrotate (emerge.log.1, emerge.log.2.gz...), from the oldest to the newest.
+    ///
+    /// The logs compressed with gzip, xz, zstd or bzip2 are always read.
+    pub include_rotated: bool,
+
+    #[arg(long, value_name = "TIMESTAMP", global = true)]
+    /// Use this time (seconds since EPOCH) as the current time, to see what would have been shown at this moment.
+    pub now: Option<u32>,
+
+    #[arg(long, global = true)]
+    /// Do not check in /proc that the emerges read from the log are still running.
+    ///
+    /// Use it when the emerges of a root are not visible from here (in a container, or on another machine).
+    pub no_proc: bool,
+
+    #[arg(long, global = true)]
+    /// Show the compilers running, the memory and the CPU used by each build (read in /proc).
+    ///
+    /// The CPU is measured during half a second, so the output is a bit slower.
+    pub resources: bool,
+
+    #[arg(long, value_name = "DURATION", default_value = "7d", value_parser = parse_duration, global = true)]
+    /// Ignore the emerges, synchronisations and unmerges started longer ago than this (seconds, or with a unit: 30m, 12h, 7d).
+    ///
+    /// They were most likely killed without writing it in the log.
+    pub max_age: u32,
+
+    #[arg(long, value_name = "DURATION", default_value = "2h", value_parser = parse_duration, global = true)]
+    /// Show an emerge as stalled if its build log was not written for this long (0 to never do it).
+    ///
+    /// Your portage need split-log in FEATURES, otherwise the emerges are never shown as stalled.
+    pub stall_after: u32,
+
+    #[arg(long = "repo", value_name = "REPOSITORY", global = true)]
+    /// Only show the packages of this repository in the history and the statistics (gentoo, guru...).
+    pub repository: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+/// The commands other than showing the running emerges
+pub enum Command {
+    /// Compute how much faster the machines given with --reference are compared to each root.
+    ///
+    /// The times of the packages emerged on both machines are compared, and the outliers are ignored.
+    /// The factor shown can be given to --reference.
+    Calibrate,
+
+    /// List the emerge sessions (one for each call to emerge) of each root.
+    Sessions,
+
+    /// Show the details of one emerge session of each root.
+    Session {
+        /// The number of the session, as shown by the command sessions
+        id: u32,
+    },
+
+    /// Show when the packages were installed, upgraded and removed, from the oldest to the newest.
+    History {
+        /// Only show this package (category/name, or only the name)
+        package: Option<String>,
+
+        #[arg(long)]
+        /// Only show the packages rebuilt with the same version, with the number of rebuilds (the most rebuilt first)
+        rebuilds: bool,
+    },
+
+    /// Show the emerge times of each package: number of emerges, average, best and worst time.
+    ///
+    /// The packages of an overlay, or emerged to another root, have their own line.
+    Stats {
+        /// Only show this package (category/name, or only the name)
+        package: Option<String>,
+    },
+
+    /// Predict each past emerge using only the emerges before it, and show how far off each estimator was.
+    ///
+    /// For each estimator: the mean absolute error, the median absolute percentage error and the part of under-predictions,
+    /// for all the packages and then for each of them.
+    Backtest {
+        /// Only show this package (category/name, or only the name)
+        package: Option<String>,
+    },
+
+    /// Show the status again and again, until stopped with Ctrl+C.
+    ///
+    /// The memory used by each build is measured in /proc, and its peak is recorded in the metrics file of the root
+    /// when the build completes, to be shown by the command stats.
+    ///
+    /// When a package or a whole mergelist completes or fails, the command given with --hook is run with the variables
+    /// GLS_EVENT (package or mergelist), GLS_PACKAGE (the package, or the arguments of emerge for a mergelist),
+    /// GLS_DURATION (in seconds), GLS_STATUS (completed or failed), GLS_ROOT, and GLS_MERGED (15/16) for a mergelist.
+    Watch {
+        #[arg(long, default_value_t = 10)]
+        /// The number of seconds between two updates
+        interval: u64,
+
+        #[arg(long)]
+        /// The command to run (with sh -c) when a package or a mergelist ends
+        hook: Option<String>,
+
+        #[cfg(feature = "notify")]
+        #[arg(long)]
+        /// Also send a desktop notification over the session D-Bus
+        notify: bool,
+    },
+
+    /// Show what would have been printed at a moment of the past: the logs are only read until this moment.
+    ///
+    /// For --full and --all, the packages emerged after this moment by the running emerges replace mtimedb.
+    Replay {
+        #[arg(long, value_parser = parse_date, verbatim_doc_comment)]
+        /// The moment to show, in local time ("2024-09-30 14:00" or "2024-09-30 14:00:03") or as a timestamp
+        at: u32,
+    },
+
+    /// List the synchronisations of the repositories (emerge --sync) of each root, with their duration.
+    SyncHistory,
+
+    /// Write every completed merge of each root on the standard output.
+    ///
+    /// The columns are: timestamp (start of the emerge), cpn, version, binary, duration (in seconds), root, file (the log the merge was read from), num (x of y), session, target_root (the root the package was emerged to), repository, slot.
+    /// The files created can be given to --reference.
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        /// The format of the output
+        format: ExportFormat,
+    },
 }
 
 #[derive(Args, Default, Debug)]
 #[group(required = false, multiple = false)]
 /// Format of the output (time for all, all packages, none of the two)
 pub struct Format {
-    #[arg(long, verbatim_doc_comment)]
+    #[arg(long, verbatim_doc_comment, global = true)]
     /// Print the total time until the end of the emerge command.
     ///
     /// When using this, the program will read the content /var/cache/edb/mtimedb, and sum the time needed to complete the current emerge.
     /// This flag add a "Total: ..." at the end of the lines of the running emerge.
     pub full: bool,
 
-    #[arg(long, verbatim_doc_comment)]
+    #[arg(long, verbatim_doc_comment, global = true)]
     /// Print the time needed for all packages in mtimedb.
     ///
     /// This flag makes the program read the content of the /var/cache/edb/mtimedb -> "resume" -> "mergelist".
@@ -108,33 +302,68 @@ pub struct Format {
     pub all: bool,
 }
 
-/// Return the current time (the number of seconds since EPOCH)
-///
-/// During tests, return 1234567890
-pub fn current_time() -> u64 {
-    #[cfg(not(test))]
-    return std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Time was warped. Fix it !")
-        .as_secs();
+impl Arguments {
+    /// Return true if `repository` is the one given with `--repo`, or if no repository was given
+    pub fn keep_repository(&self, repository: &str) -> bool {
+        self.repository.as_ref().is_none_or(|r| r == repository)
+    }
 
-    #[cfg(test)]
-    return 1234567890;
-}
+    /// Return true if something started at `start` is too old to still be running at `now` (see `--max-age`)
+    pub fn is_too_old(&self, start: u32, now: u32) -> bool {
+        now.saturating_sub(start) > self.max_age
+    }
 
-/// Return the path to mtimedb
-///
-/// During tests, return the root
-pub fn get_path_mtimedb(root: &str) -> String {
-    let mut path = String::new();
+    /// Return the clock to use: the time given with `--now`, or the one of the system
+    pub fn clock(&self) -> Box<dyn Clock> {
+        match self.now {
+            Some(now) => Box::new(FixedClock(now)),
+            None => Box::new(SystemClock),
+        }
+    }
 
-    #[cfg(not(test))]
-    correct_path(root, "/var/cache/edb/mtimedb", &mut path);
+    /// Return the roots to read, either the ones declared with `--root`, or every fakeroot with every file
+    ///
+    /// With `--include-rotated`, the rotated logs are added before each log.
+    pub fn get_roots(&self) -> Vec<Root> {
+        let mut roots: Vec<Root> = if self.roots.is_empty() {
+            self.fakeroots
+                .iter()
+                .map(|fakeroot| Root::from_fakeroot(fakeroot, &self.files, &Paths::default()))
+                .collect()
+        } else {
+            self.roots.clone()
+        };
+        if self.include_rotated {
+            for root in &mut roots {
+                root.logs = root.logs.iter().flat_map(|l| with_rotated(l)).collect();
+            }
+        }
+        roots
+    }
 
-    #[cfg(test)]
-    path.push_str(root);
+    /// Read the histories of all the machines given with `--reference`
+    ///
+    /// The sources that could not be read are reported (unless `--skip-file`) and ignored.
+    pub fn load_references(&self) -> Vec<Reference> {
+        let mut references = Vec::new();
+        for source in &self.references {
+            let what = format!("reference {}", source.name);
+            if let Some(r) = report_error(Reference::load(source), &what, self.skip_file) {
+                references.push(r);
+            }
+        }
+        references
+    }
+}
 
-    path
+/// Return the current time of the system (the number of seconds since EPOCH)
+///
+/// Use a [`Clock`] to be able to change it.
+pub fn current_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time was warped. Fix it !")
+        .as_secs()
 }
 
 /// Test wether or not `c` is a digit
@@ -142,27 +371,6 @@ pub fn is_digit(c: &u8) -> bool {
     (b'0' <= *c) && (*c <= b'9')
 }
 
-/// Compute the size of category/name from category/name-version
-///
-/// Grow the window until the character after a `-` is a digit.  
-/// Theorically, could give false positive, in practice, I don't care.
-pub fn get_size_cpn(cpnpv: &str) -> Option<usize> {
-    let mut n = 0;
-    let mut found: bool = false;
-    while !found {
-        match cpnpv[n..].find('-') {
-            Some(value) => n += value + 1,
-            None => {
-                n = cpnpv.len() + 1;
-                break;
-            }
-        }
-        found = is_digit(cpnpv.as_bytes().get(n)?);
-    }
-
-    Some(n - 1)
-}
-
 /// Put both `root` and `file` in `path` while removing or adding trailing slash to avoid problem in the functions used after
 pub fn correct_path(root: &str, file: &str, path: &mut String) {
     if !file.starts_with(".") {
@@ -179,6 +387,42 @@ pub fn correct_path(root: &str, file: &str, path: &mut String) {
     path.push_str(&file[start_file..]);
 }
 
+/// Format a duration in seconds, with the seconds if it is less than a minute
+///
+/// Longer durations use [`Atom::convert_text`].
+pub fn format_duration(time: u32) -> String {
+    if time < 60 {
+        return format!("{time}s");
+    }
+    let mut out = String::new();
+    Atom::convert_text(time as f64, &mut out);
+    out.trim_end().to_string()
+}
+
+/// Parse a duration given in seconds, or as a number followed by a unit (s, m, h or d)
+///
+/// # Examples
+/// `90`, `30m`, `12h` or `7d`
+pub fn parse_duration(text: &str) -> Result<u32, String> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("invalid unit in '{text}', use s, m, h or d")),
+    };
+    let number: u32 = number
+        .parse()
+        .map_err(|e| format!("invalid duration '{text}': {e}"))?;
+    number
+        .checked_mul(factor)
+        .ok_or(format!("'{text}' is too long"))
+}
+
 /// Return the sum of `total` and `t` if both are greater than 0
 pub fn add_time(total: f64, t: f64) -> f64 {
     if (t < 0.0) || (total < 0.0) {
@@ -201,41 +445,6 @@ mod tests {
         assert!(is_digit(&b'6'));
     }
     #[test]
-    fn test_size_cpn_simple() {
-        let a = "hello";
-        let s_a = get_size_cpn(a).unwrap();
-        assert_eq!(s_a, a.len());
-    }
-
-    #[test]
-    fn test_size_cpn_dash() {
-        let a = "sys-devel/gcc";
-        let s_a = get_size_cpn(a).unwrap();
-        assert_eq!(s_a, a.len());
-    }
-
-    #[test]
-    fn test_size_cpn_version() {
-        let gcc = "sys-devel/gcc";
-        let a = "sys-devel/gcc-12.4.0";
-
-        let s_a = get_size_cpn(a).unwrap();
-
-        assert_eq!(s_a, gcc.len());
-    }
-
-    #[test]
-    fn test_size_cpn_hard() {
-        let a = "dev-python/PyQt6-6.7.1-r1";
-        let s_a = get_size_cpn(a).unwrap();
-        assert_eq!(s_a, 16);
-    }
-
-    #[test]
-    fn correct_time() {
-        assert_eq!(current_time(), 1234567890);
-    }
-    #[test]
     fn correct_path_classical() {
         let root = "/";
         let file = "/var/log/emerge.log";
@@ -269,9 +478,20 @@ mod tests {
     }
 
     #[test]
-    fn path_mtimedb() {
-        assert_eq!(get_path_mtimedb("hey"), "hey");
-        assert_eq!(get_path_mtimedb("/ha/l/lo"), "/ha/l/lo");
+    fn duration_format() {
+        assert_eq!(format_duration(42), "42s");
+        assert_eq!(format_duration(3 * 60 + 10), "3m");
+        assert_eq!(format_duration(60 * 60), "1h");
+    }
+
+    #[test]
+    fn duration_parse() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30m"), Ok(30 * 60));
+        assert_eq!(parse_duration("7d"), Ok(7 * 24 * 60 * 60));
+        assert!(parse_duration("2w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("100000d").is_err());
     }
 
     #[test]
@@ -0,0 +1,406 @@
+#![warn(missing_docs)]
+
+//! Follow the last log of a root while emerge runs, for `gls watch`
+
+// // // // // // // // // // // // // // // // // // // // // // // //
+//
+// genlogsum: GENtoo LOG SUMmary, summarize log to show running emerge
+// Copyright (C) 2024 Henri GASC
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+// // // // // // // // // // // // // // // // // // // // // // // //
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+};
+
+use crate::{
+    logfile::{complete_size, for_each_line, plain_size},
+    package::{Merge, PackageInfo},
+    parse_file::{act_on_line, read_plain, LogState},
+    read_log, report_error,
+    resources::Resources,
+    root::Root,
+    session::{Session, SessionStatus},
+};
+
+/// A package that is not being emerged anymore
+pub struct Ended {
+    /// The package, as it was when it started
+    pub package: PackageInfo,
+    /// Its merge if the emerge completed, `None` if it failed or was stopped
+    pub merge: Option<Merge>,
+    /// The highest memory used by the build while it was watched, in kB
+    pub peak_rss: Option<u64>,
+}
+
+/// What changed in the last log since the previous update
+#[derive(Default)]
+pub struct Changes {
+    /// The packages whose emerge ended
+    pub ended: Vec<Ended>,
+    /// The sessions that ended (see [`Session::has_ended`])
+    pub sessions: Vec<Session>,
+}
+
+/// What we follow of a root: all its logs are read once, then only the lines added to the last one
+pub struct Watcher {
+    /// What was learned from the logs, without what was already reported (see [`Watcher::update`])
+    pub state: LogState,
+    /// Where the last log was read until
+    offset: u64,
+    /// The highest memory used by each running build, in kB, by full name
+    peaks: HashMap<String, u64>,
+}
+
+impl Watcher {
+    /// Start following `root`, after reading all its logs (as [`read_root`](crate::read_root))
+    ///
+    /// The last log is only read until its last end of line: the line being written is read by the first [`Watcher::update`].
+    /// The errors are reported unless `skip_file`. What ended before is not returned by [`Watcher::update`].
+    pub fn new(root: &Root, skip_file: bool) -> Self {
+        let mut state = LogState::default();
+        let mut offset = 0;
+        if let Some((last, previous)) = root.logs.split_last() {
+            for path in previous {
+                read_log(path, skip_file, &mut state);
+            }
+            match complete_size(last) {
+                Some(size) => {
+                    report_error(read_plain(last, size, &mut state), last, skip_file);
+                    offset = size;
+                }
+                // A compressed log does not grow
+                None => read_log(last, skip_file, &mut state),
+            }
+        }
+        state.read_vdb_slots(&root.paths.vdb);
+        let mut watcher = Self {
+            state,
+            offset,
+            peaks: HashMap::new(),
+        };
+        watcher.prune();
+        watcher
+    }
+
+    /// Keep the highest memory used by each running build (see [`Liveness::measure`](crate::liveness::Liveness::measure))
+    pub fn record_peaks(&mut self, resources: &HashMap<String, Resources>) {
+        for (full_name, r) in resources {
+            let peak = self.peaks.entry(full_name.clone()).or_default();
+            *peak = std::cmp::max(*peak, r.rss);
+        }
+    }
+
+    /// Read the lines added to the last log of `root`, and return what changed
+    ///
+    /// A log that became smaller was rotated, and is read again from the start. The compressed logs do not grow.
+    /// What changed is then forgotten (see [`Watcher::prune`]).
+    pub fn update(&mut self, root: &Root) -> io::Result<Changes> {
+        let Some(last) = root.logs.last() else {
+            return Ok(Changes::default());
+        };
+        let Some(size) = plain_size(last) else {
+            return Ok(Changes::default());
+        };
+        if size < self.offset {
+            self.offset = 0;
+        }
+
+        let mut file = File::open(last)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut added = Vec::new();
+        file.take(size - self.offset).read_to_end(&mut added)?;
+        let changes = self.act_on_bytes(&added)?;
+        self.prune();
+        Ok(changes)
+    }
+
+    /// Forget the sessions that ended and the merges, so that the state does not grow while watching
+    ///
+    /// The times of the packages stay in the atoms. The last merge of each package is kept,
+    /// as the kind of the next one is found from it (binary merges included).
+    fn prune(&mut self) {
+        self.state.sessions.retain(|s| !s.has_ended());
+        let mut seen = HashSet::new();
+        let mut merges: Vec<Merge> = std::mem::take(&mut self.state.merges)
+            .into_iter()
+            .rev()
+            .filter(|m| seen.insert((m.cpn.clone(), m.target_root.clone())))
+            .collect();
+        merges.reverse();
+        self.state.merges = merges;
+    }
+
+    /// Act on the complete lines of `added`, and return what changed
+    ///
+    /// The emerges that leave [`LogState::emerges_not_complete`] ended, and the sessions that were running
+    /// (or that started in `added`) and have now ended are returned.
+    /// The emerges of a session that was killed (see [`SessionStatus::Interrupted`]) are failed with it.
+    fn act_on_bytes(&mut self, added: &[u8]) -> io::Result<Changes> {
+        // The line being written is read at the next update
+        let complete = added.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        let before = self.state.merges.len();
+        let running = self.state.emerges_not_complete.clone();
+        let first_running = self
+            .state
+            .sessions
+            .iter()
+            .position(|s| !s.has_ended())
+            .unwrap_or(self.state.sessions.len());
+        for_each_line(&added[..complete], |line| {
+            act_on_line(line, &mut self.state)
+        })?;
+        self.offset += complete as u64;
+
+        let merges = &self.state.merges[before..];
+        let mut ended: Vec<Ended> = running
+            .into_iter()
+            .filter(|(full_name, _)| !self.state.emerges_not_complete.contains_key(full_name))
+            .map(|(full_name, package)| Ended {
+                merge: merges
+                    .iter()
+                    .find(|m| {
+                        (m.time == package.time)
+                            && (format!("{}-{}", m.cpn, m.version) == full_name)
+                    })
+                    .cloned(),
+                peak_rss: self.peaks.remove(&full_name),
+                package,
+            })
+            .collect();
+
+        // The log keeps the emerges of a killed session until the next '*** terminating.'
+        for i in first_running..self.state.sessions.len() {
+            if self.state.sessions[i].status != SessionStatus::Interrupted {
+                continue;
+            }
+            // The emerges started by the next session are not the killed ones, even for the same package
+            let next_start = self.state.sessions.get(i + 1).map_or(u32::MAX, |s| s.start);
+            let attempted = &self.state.sessions[i].attempted;
+            let killed: Vec<String> = self
+                .state
+                .emerges_not_complete
+                .iter()
+                .filter(|(full_name, p)| attempted.contains(full_name) && (p.time < next_start))
+                .map(|(full_name, _)| full_name.clone())
+                .collect();
+            for full_name in killed {
+                if let Some(package) = self.state.emerges_not_complete.remove(&full_name) {
+                    ended.push(Ended {
+                        package,
+                        merge: None,
+                        peak_rss: self.peaks.remove(&full_name),
+                    });
+                }
+                self.state.sessions[i].failed.push(full_name);
+            }
+        }
+
+        let sessions = self.state.sessions[first_running..]
+            .iter()
+            .filter(|s| s.has_ended())
+            .cloned()
+            .collect();
+        Ok(Changes { ended, sessions })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher() -> Watcher {
+        Watcher {
+            state: LogState::default(),
+            offset: 0,
+            peaks: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn ended_packages() {
+        let mut watcher = watcher();
+        let start = b"1234567890:  >>> emerge (1 of 2) app/first-1.0 to /\n\
+1234567890:  >>> emerge (2 of 2) app/second-2.0 to /\n";
+        assert!(watcher.act_on_bytes(start).unwrap().ended.is_empty());
+        assert_eq!(watcher.offset, start.len() as u64);
+        watcher.record_peaks(&HashMap::from([(
+            "app/first-1.0".to_string(),
+            Resources {
+                rss: 2048,
+                ..Resources::default()
+            },
+        )]));
+
+        // The end of the second line is not written yet
+        let end = b"1234567900:  ::: completed emerge (1 of 2) app/first-1.0 to /\n\
+1234567910:  *** termin";
+        let ended = watcher.act_on_bytes(end).unwrap().ended;
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].package.full_name, "app/first-1.0");
+        assert_eq!(ended[0].merge.as_ref().unwrap().duration, 10);
+        assert_eq!(ended[0].peak_rss, Some(2048));
+        assert_eq!(watcher.offset, (start.len() + 62) as u64);
+
+        let ended = watcher
+            .act_on_bytes(b"1234567910:  *** terminating.\n")
+            .unwrap()
+            .ended;
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].package.full_name, "app/second-2.0");
+        assert!(ended[0].merge.is_none());
+        assert_eq!(ended[0].peak_rss, None);
+    }
+
+    #[test]
+    fn ended_sessions() {
+        let mut watcher = watcher();
+        let first = b"1234567890: Started emerge on: Feb 13, 2009 23:31:30\n\
+1234567890:  *** emerge --oneshot app/first\n\
+1234567890:  >>> emerge (1 of 1) app/first-1.0 to /\n";
+        assert!(watcher.act_on_bytes(first).unwrap().sessions.is_empty());
+
+        // The exit is only known at '*** terminating.'
+        let exit = b"1234567900:  ::: completed emerge (1 of 1) app/first-1.0 to /\n\
+1234567900:  *** exiting successfully.\n";
+        assert!(watcher.act_on_bytes(exit).unwrap().sessions.is_empty());
+        let sessions = watcher
+            .act_on_bytes(b"1234567901:  *** terminating.\n")
+            .unwrap()
+            .sessions;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].status, SessionStatus::Success);
+        assert_eq!(sessions[0].end, Some(1234567901));
+
+        // A session killed is seen when the next one starts, and is not returned again
+        let killed = b"1234567910: Started emerge on: Feb 13, 2009 23:31:50\n\
+1234567910:  >>> emerge (1 of 1) app/second-2.0 to /\n\
+1234567920: Started emerge on: Feb 13, 2009 23:32:00\n";
+        let changes = watcher.act_on_bytes(killed).unwrap();
+        assert_eq!(changes.sessions.len(), 1);
+        assert_eq!(changes.sessions[0].id, 2);
+        assert_eq!(changes.sessions[0].status, SessionStatus::Interrupted);
+        assert_eq!(changes.sessions[0].failed, vec!["app/second-2.0"]);
+        assert_eq!(changes.ended.len(), 1);
+        assert_eq!(changes.ended[0].package.full_name, "app/second-2.0");
+        assert!(changes.ended[0].merge.is_none());
+        assert!(watcher.state.emerges_not_complete.is_empty());
+        let sessions = watcher
+            .act_on_bytes(b"1234567930:  *** terminating.\n")
+            .unwrap()
+            .sessions;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, 3);
+    }
+
+    #[test]
+    fn never_terminated() {
+        let root = Root::from_fakeroot(
+            "/",
+            &["./tests/emerge.log/binary_running".to_string()],
+            &crate::root::Paths::default(),
+        );
+        let watcher = Watcher::new(&root, false);
+        assert!(watcher
+            .state
+            .emerges_not_complete
+            .contains_key("category/package-1.2.3"));
+    }
+
+    #[test]
+    fn killed_then_restarted() {
+        let mut watcher = watcher();
+        let log = b"1234567890: Started emerge on: Feb 13, 2009 23:31:30\n\
+1234567890:  >>> emerge (1 of 1) app/first-1.0 to /\n\
+1234567900: Started emerge on: Feb 13, 2009 23:31:40\n\
+1234567900:  >>> emerge (1 of 1) app/first-1.0 to /\n";
+        let changes = watcher.act_on_bytes(log).unwrap();
+        // The emerge started again is still running
+        assert_eq!(changes.sessions.len(), 1);
+        assert!(changes.ended.is_empty());
+        assert_eq!(
+            watcher.state.emerges_not_complete["app/first-1.0"].time,
+            1234567900
+        );
+    }
+
+    #[test]
+    fn started_mid_line() {
+        let root = Root::from_fakeroot(
+            "/",
+            &["./tests/emerge.log/partial".to_string()],
+            &crate::root::Paths::default(),
+        );
+        let mut watcher = Watcher::new(&root, false);
+        assert_eq!(watcher.offset, 97);
+        assert_eq!(watcher.state.sessions[0].args, "--oneshot app/first");
+        // The end of the line is written
+        watcher
+            .act_on_bytes(b"1234567890:  >>> emerge (1 of 1) app/first-1.0 to /\n")
+            .unwrap();
+        assert!(watcher
+            .state
+            .emerges_not_complete
+            .contains_key("app/first-1.0"));
+    }
+
+    #[test]
+    fn prune_reported() {
+        let mut watcher = watcher();
+        let log = b"1234567890: Started emerge on: Feb 13, 2009 23:31:30\n\
+1234567890:  >>> emerge (1 of 2) app/first-1.0 to /\n\
+1234567900:  ::: completed emerge (1 of 2) app/first-1.0 to /\n\
+1234567900:  >>> emerge (2 of 2) app/first-1.1 to /\n\
+1234567910:  ::: completed emerge (2 of 2) app/first-1.1 to /\n\
+1234567910:  *** terminating.\n\
+1234567920: Started emerge on: Feb 13, 2009 23:32:00\n";
+        watcher.act_on_bytes(log).unwrap();
+        watcher.prune();
+        assert_eq!(watcher.state.sessions.len(), 1);
+        assert_eq!(watcher.state.sessions[0].start, 1234567920);
+        assert_eq!(watcher.state.merges.len(), 1);
+        assert_eq!(watcher.state.merges[0].version, "1.1");
+        assert_eq!(watcher.state.completed_atoms["app/first"].num_emerge, 2);
+
+        // The ids of the sessions removed are not given again
+        watcher
+            .act_on_bytes(b"1234567930: Started emerge on: Feb 13, 2009 23:32:10\n")
+            .unwrap();
+        watcher.prune();
+        assert_eq!(watcher.state.sessions[0].id, 3);
+        assert_eq!(watcher.state.session_id(), 3);
+    }
+
+    #[test]
+    fn update_from_log() {
+        let root = Root::from_fakeroot(
+            "/",
+            &["./tests/emerge.log/sessions".to_string()],
+            &crate::root::Paths::default(),
+        );
+        let mut watcher = Watcher::new(&root, false);
+        let running = watcher.state.emerges_not_complete.len();
+        assert!(running > 0);
+        // The history is kept, but not the sessions that ended
+        assert!(!watcher.state.completed_atoms.is_empty());
+        assert!(watcher.state.sessions.iter().all(|s| !s.has_ended()));
+        // Nothing was added to the log
+        let changes = watcher.update(&root).unwrap();
+        assert!(changes.ended.is_empty() && changes.sessions.is_empty());
+        assert_eq!(watcher.state.emerges_not_complete.len(), running);
+    }
+}